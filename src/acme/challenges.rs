@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::http::{Res, ResponseBuilder};
+
+/// Path prefix the CA fetches HTTP-01 key authorizations from.
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Whether `path` is under the challenge route.
+pub fn is_challenge_path(path: &str) -> bool {
+    path.starts_with(CHALLENGE_PATH)
+}
+
+/// Key authorizations for one server's pending challenges, keyed by token.
+/// Its certificate manager fills them in and its plain HTTP listeners
+/// answer from them.
+#[derive(Debug, Clone, Default)]
+pub struct Challenges {
+    pending: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl Challenges {
+    pub(crate) fn insert(&self, token: &str, key_authorization: String) {
+        self.pending
+            .write()
+            .unwrap()
+            .insert(token.to_string(), key_authorization);
+    }
+
+    pub(crate) fn remove(&self, token: &str) {
+        self.pending.write().unwrap().remove(token);
+    }
+
+    /// Answers the CA's HTTP-01 validation request for `path`, without going
+    /// through the application's routes.
+    pub fn response(&self, path: &str) -> Res {
+        let pending = self.pending.read().unwrap();
+        let token = path.strip_prefix(CHALLENGE_PATH).unwrap_or_default();
+        match pending.get(token) {
+            Some(key_authorization) => Res::new(
+                ResponseBuilder::ok()
+                    .content_type("application/octet-stream")
+                    .body(key_authorization.clone())
+                    .build(),
+                200,
+            ),
+            None => Res::new(ResponseBuilder::not_found().text("Not Found").build(), 404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_only_its_own_pending_tokens() {
+        let challenges = Challenges::default();
+        let other = Challenges::default();
+        challenges.insert("abc", "abc.thumbprint".to_string());
+
+        let path = format!("{}abc", CHALLENGE_PATH);
+        assert_eq!(challenges.response(&path).status, 200);
+        assert_eq!(other.response(&path).status, 404);
+
+        challenges.remove("abc");
+        assert_eq!(challenges.response(&path).status, 404);
+    }
+}
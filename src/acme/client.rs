@@ -0,0 +1,249 @@
+use std::{io, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, PKCS_ECDSA_P256_SHA256};
+use serde_json::{json, Value};
+
+use super::{
+    acme_error,
+    challenges::Challenges,
+    http::{HttpsClient, Response},
+    jws::AccountKey,
+};
+
+const JOSE_JSON: &str = "application/jose+json";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+
+/// A newly issued certificate chain and its private key, both PEM.
+pub struct Issued {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+/// Talks to one ACME v2 directory (RFC 8555) on behalf of one account.
+pub struct AcmeClient {
+    http: HttpsClient,
+    key: AccountKey,
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    /// The account URL, used as `kid` once the account exists.
+    account: Option<String>,
+    nonce: Option<String>,
+    /// Where pending key authorizations are published for the CA to fetch.
+    challenges: Challenges,
+}
+
+impl AcmeClient {
+    pub async fn connect(
+        http: HttpsClient,
+        directory_url: &str,
+        key: AccountKey,
+        challenges: Challenges,
+    ) -> io::Result<Self> {
+        let directory = http.get(directory_url).await?;
+        if directory.status != 200 {
+            return Err(acme_error(format!(
+                "directory {} returned {}",
+                directory_url, directory.status
+            )));
+        }
+        let directory = directory.json()?;
+        let url = |name: &str| {
+            directory[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| acme_error(format!("directory has no {}", name)))
+        };
+
+        Ok(Self {
+            new_nonce: url("newNonce")?,
+            new_account: url("newAccount")?,
+            new_order: url("newOrder")?,
+            http,
+            key,
+            account: None,
+            nonce: None,
+            challenges,
+        })
+    }
+
+    /// Registers the account, or finds the existing one for this key.
+    pub async fn register(&mut self, contact: &[String]) -> io::Result<()> {
+        let contact: Vec<String> = contact
+            .iter()
+            .map(|email| match email.starts_with("mailto:") {
+                true => email.clone(),
+                false => format!("mailto:{}", email),
+            })
+            .collect();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+
+        let url = self.new_account.clone();
+        let response = self.post(&url, Some(&payload)).await?;
+        let account = response
+            .header("location")
+            .ok_or_else(|| acme_error("account response has no Location"))?;
+        self.account = Some(account.to_string());
+        Ok(())
+    }
+
+    /// Orders a certificate for `domains`, answering the HTTP-01 challenges
+    /// through the shared challenge route.
+    pub async fn issue(&mut self, domains: &[String]) -> io::Result<Issued> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let url = self.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = response
+            .header("location")
+            .ok_or_else(|| acme_error("order response has no Location"))?
+            .to_string();
+        let order = response.json()?;
+
+        for authorization in string_list(&order["authorizations"]) {
+            self.authorize(&authorization).await?;
+        }
+
+        self.poll(&order_url, &["ready", "valid"]).await?;
+
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).map_err(acme_error)?;
+        let csr = csr(domains, &key)?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| acme_error("order has no finalize URL"))?;
+        self.post(finalize, Some(&json!({ "csr": csr }))).await?;
+
+        let order = self.poll(&order_url, &["valid"]).await?;
+        let certificate = order["certificate"]
+            .as_str()
+            .ok_or_else(|| acme_error("valid order has no certificate URL"))?;
+        let cert = self.post(certificate, None).await?;
+
+        Ok(Issued {
+            cert_pem: String::from_utf8_lossy(&cert.body).into_owned(),
+            key_pem: key.serialize_pem(),
+        })
+    }
+
+    async fn authorize(&mut self, url: &str) -> io::Result<()> {
+        let authorization = self.post(url, None).await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|list| list.iter().find(|c| c["type"] == "http-01"))
+            .ok_or_else(|| acme_error("authorization offers no http-01 challenge"))?;
+        let token = challenge["token"]
+            .as_str()
+            .ok_or_else(|| acme_error("challenge has no token"))?
+            .to_string();
+        let challenge_url = challenge["url"]
+            .as_str()
+            .ok_or_else(|| acme_error("challenge has no URL"))?
+            .to_string();
+
+        let key_authorization = format!("{}.{}", token, self.key.thumbprint());
+        self.challenges.insert(&token, key_authorization);
+        let result = async {
+            self.post(&challenge_url, Some(&json!({}))).await?;
+            self.poll(url, &["valid"]).await
+        }
+        .await;
+        self.challenges.remove(&token);
+        result.map(|_| ())
+    }
+
+    /// POST-as-GETs `url` until its status is one of `done`. Fails on
+    /// `invalid`, with the CA's explanation where it gave one.
+    async fn poll(&mut self, url: &str, done: &[&str]) -> io::Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = self.post(url, None).await?.json()?;
+            let status = object["status"].as_str().unwrap_or_default();
+            if done.contains(&status) {
+                return Ok(object);
+            }
+            if status == "invalid" {
+                let detail = object["challenges"]
+                    .as_array()
+                    .and_then(|list| list.iter().find_map(|c| c["error"]["detail"].as_str()))
+                    .or_else(|| object["error"]["detail"].as_str())
+                    .unwrap_or("no detail given");
+                return Err(acme_error(format!("{} is invalid: {}", url, detail)));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(acme_error(format!("gave up waiting on {}", url)))
+    }
+
+    /// Sends a signed request, retrying once if the CA rejects the nonce.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> io::Result<Response> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.fresh_nonce().await?,
+            };
+            let body = self
+                .key
+                .sign(url, &nonce, self.account.as_deref(), payload)?;
+            let response = self.http.post(url, JOSE_JSON, &body).await?;
+            self.nonce = response.header("replay-nonce").map(str::to_string);
+
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem = response.json().unwrap_or_default();
+            let kind = problem["type"].as_str().unwrap_or_default();
+            if kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(acme_error(format!(
+                "{} returned {}: {}",
+                url,
+                response.status,
+                problem["detail"].as_str().unwrap_or(kind)
+            )));
+        }
+    }
+
+    async fn fresh_nonce(&self) -> io::Result<String> {
+        self.http
+            .head(&self.new_nonce)
+            .await?
+            .header("replay-nonce")
+            .map(str::to_string)
+            .ok_or_else(|| acme_error("newNonce returned no Replay-Nonce"))
+    }
+}
+
+/// A DER certificate signing request for `domains`, base64url encoded.
+fn csr(domains: &[String], key: &KeyPair) -> io::Result<String> {
+    let mut params = CertificateParams::new(domains.to_vec()).map_err(acme_error)?;
+    let mut name = DistinguishedName::new();
+    if let Some(domain) = domains.first() {
+        name.push(DnType::CommonName, domain.as_str());
+    }
+    params.distinguished_name = name;
+    let request = params.serialize_request(key).map_err(acme_error)?;
+    Ok(URL_SAFE_NO_PAD.encode(request.der()))
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|list| {
+            list.iter()
+                .filter_map(|v| v.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,96 @@
+use std::{path::PathBuf, time::Duration};
+
+pub const LETS_ENCRYPT_PRODUCTION: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const LETS_ENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+
+/// CA bundle used to reach the ACME server when none is configured.
+const SYSTEM_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Obtains and renews the server certificate from an ACME v2 CA such as
+/// Let's Encrypt, proving control of `domains` with HTTP-01 challenges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcmeConfig {
+    /// Names the certificate covers. The first one is its subject.
+    pub domains: Vec<String>,
+    /// Contact addresses for the account, such as `admin@example.com`.
+    pub contact: Vec<String>,
+    /// The CA's directory. Point this at a local Pebble instance, such as
+    /// `https://localhost:14000/dir`, for testing.
+    pub directory_url: String,
+    /// PEM bundle trusted when connecting to the directory. Pebble needs its
+    /// own `pebble.minica.pem` here.
+    pub ca_bundle: PathBuf,
+    /// Where the account key, certificate and its key are kept.
+    pub storage_dir: PathBuf,
+    /// Plain HTTP port that answers challenges. The CA always validates on
+    /// port 80, but Pebble can be told to use another. It's listened on at
+    /// each host the TCP binds use, serving nothing but challenges unless
+    /// it's also the redirect port.
+    pub http_port: u16,
+    /// Renew once the certificate expires within this long.
+    pub renew_before: Duration,
+    /// How often to check whether the certificate needs renewing.
+    pub check_interval: Duration,
+}
+
+impl AcmeConfig {
+    pub fn new<S: Into<String>>(domains: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            domains: domains.into_iter().map(Into::into).collect(),
+            contact: Vec::new(),
+            directory_url: LETS_ENCRYPT_PRODUCTION.to_string(),
+            ca_bundle: PathBuf::from(SYSTEM_CA_BUNDLE),
+            storage_dir: PathBuf::from("acme"),
+            http_port: 80,
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            check_interval: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+
+    pub fn contact(mut self, email: impl Into<String>) -> Self {
+        self.contact.push(email.into());
+        self
+    }
+
+    pub fn directory_url(mut self, url: impl Into<String>) -> Self {
+        self.directory_url = url.into();
+        self
+    }
+
+    pub fn ca_bundle(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_bundle = path.into();
+        self
+    }
+
+    pub fn storage_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.storage_dir = path.into();
+        self
+    }
+
+    pub fn http_port(mut self, port: u16) -> Self {
+        self.http_port = port;
+        self
+    }
+
+    pub fn renew_before(mut self, duration: Duration) -> Self {
+        self.renew_before = duration;
+        self
+    }
+
+    pub fn check_interval(mut self, interval: Duration) -> Self {
+        self.check_interval = interval;
+        self
+    }
+
+    pub fn cert_path(&self) -> PathBuf {
+        self.storage_dir.join("cert.pem")
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        self.storage_dir.join("key.pem")
+    }
+
+    pub fn account_key_path(&self) -> PathBuf {
+        self.storage_dir.join("account.pem")
+    }
+}
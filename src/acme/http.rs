@@ -0,0 +1,228 @@
+use std::{collections::HashMap, io, path::Path, sync::Arc, time::Duration};
+
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+
+use super::acme_error;
+use crate::tls::load_certs;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Far more than any directory, order or certificate chain needs.
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// A response from the ACME server.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// Keyed by lowercase name.
+    headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|v| v.as_str())
+    }
+
+    pub fn json(&self) -> io::Result<serde_json::Value> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| acme_error(format!("invalid JSON from ACME server: {}", e)))
+    }
+}
+
+/// Just enough of an HTTPS client to talk to an ACME server: one request
+/// per connection, with the body read until the server closes it.
+pub struct HttpsClient {
+    connector: TlsConnector,
+}
+
+impl HttpsClient {
+    pub fn new(ca_bundle: &Path) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        let (_, ignored) = roots.add_parsable_certificates(load_certs(ca_bundle)?);
+        if roots.is_empty() {
+            return Err(acme_error(format!(
+                "no usable CA certificates in {} ({} ignored)",
+                ca_bundle.display(),
+                ignored
+            )));
+        }
+
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(acme_error)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    pub async fn get(&self, url: &str) -> io::Result<Response> {
+        self.request("GET", url, None).await
+    }
+
+    pub async fn head(&self, url: &str) -> io::Result<Response> {
+        self.request("HEAD", url, None).await
+    }
+
+    pub async fn post(&self, url: &str, content_type: &str, body: &[u8]) -> io::Result<Response> {
+        self.request("POST", url, Some((content_type, body))).await
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<(&str, &[u8])>,
+    ) -> io::Result<Response> {
+        tokio::time::timeout(REQUEST_TIMEOUT, self.send(method, url, body))
+            .await
+            .map_err(|_| acme_error(format!("{} {} timed out", method, url)))?
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<(&str, &[u8])>,
+    ) -> io::Result<Response> {
+        let (host, port, path) = parse_url(url)?;
+        let server_name = ServerName::try_from(host.to_string()).map_err(acme_error)?;
+
+        let tcp = TcpStream::connect((host, port)).await?;
+        let mut stream = self.connector.connect(server_name, tcp).await?;
+
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rust-tcp-srv\r\nConnection: close\r\n",
+            method, path, host
+        );
+        if let Some((content_type, body)) = body {
+            request.push_str(&format!(
+                "Content-Type: {}\r\nContent-Length: {}\r\n",
+                content_type,
+                body.len()
+            ));
+        }
+        request.push_str("\r\n");
+
+        stream.write_all(request.as_bytes()).await?;
+        if let Some((_, body)) = body {
+            stream.write_all(body).await?;
+        }
+        stream.flush().await?;
+
+        let mut raw = Vec::new();
+        // One byte over the limit tells a response that is too large apart
+        // from one that fits exactly.
+        match (&mut stream)
+            .take(MAX_RESPONSE_SIZE + 1)
+            .read_to_end(&mut raw)
+            .await
+        {
+            Ok(_) => {}
+            // Some servers close without a TLS close_notify.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => {}
+            Err(e) => return Err(e),
+        }
+        if raw.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(acme_error(format!(
+                "{} {} returned more than {} bytes",
+                method, url, MAX_RESPONSE_SIZE
+            )));
+        }
+        parse_response(&raw, method == "HEAD")
+    }
+}
+
+/// Splits an `https://` URL into host, port and path.
+fn parse_url(url: &str) -> io::Result<(&str, u16, &str)> {
+    let rest = url
+        .strip_prefix("https://")
+        .ok_or_else(|| acme_error(format!("ACME URLs must use https: {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    // IPv6 literals are bracketed, so their colons aren't port separators.
+    let (host, port) = match authority.rfind(':') {
+        Some(pos) if !authority[pos..].contains(']') => {
+            let port = authority[pos + 1..]
+                .parse()
+                .map_err(|_| acme_error(format!("invalid port in {}", url)))?;
+            (&authority[..pos], port)
+        }
+        _ => (authority, 443),
+    };
+    Ok((
+        host.trim_start_matches('[').trim_end_matches(']'),
+        port,
+        path,
+    ))
+}
+
+fn parse_response(raw: &[u8], head_only: bool) -> io::Result<Response> {
+    let head_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| acme_error("truncated response from ACME server"))?;
+    let head = String::from_utf8_lossy(&raw[..head_end]);
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| acme_error("invalid status line from ACME server"))?;
+
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let mut body = if head_only {
+        Vec::new()
+    } else {
+        raw[head_end + 4..].to_vec()
+    };
+    if headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+    {
+        body = decode_chunked(&body)?;
+    } else if let Some(len) = headers.get("content-length").and_then(|l| l.parse().ok()) {
+        body.truncate(len);
+    }
+
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn decode_chunked(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = || acme_error("invalid chunked body from ACME server");
+    let mut body = Vec::new();
+    loop {
+        let line_end = data
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid())?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend_from_slice(data.get(..size).ok_or_else(invalid)?);
+        data = data.get(size + 2..).ok_or_else(invalid)?;
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    fs, io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use super::{client::AcmeClient, http::HttpsClient, jws::AccountKey, AcmeConfig, Challenges};
+use crate::{
+    logger::{LogLevel, Logger},
+    shutdown::Shutdown,
+    tls::{load_certs, load_key, write_private, ReloadableAcceptor},
+};
+
+/// Keeps the certificate in `storage_dir` issued and renewed.
+pub struct CertificateManager {
+    config: AcmeConfig,
+    challenges: Challenges,
+    logger: Logger,
+}
+
+impl CertificateManager {
+    /// Publishes key authorizations in `challenges` while orders are
+    /// validated, for the server's plain HTTP listeners to answer with.
+    pub fn new(config: AcmeConfig, challenges: Challenges) -> Self {
+        Self {
+            config,
+            challenges,
+            logger: Logger::new(),
+        }
+    }
+
+    /// Issues a certificate if there is none yet or the current one is close
+    /// to expiring. Returns whether a new one was written.
+    pub async fn ensure_certificate(&self) -> io::Result<bool> {
+        match self.expires_in() {
+            Some(remaining) if remaining > self.config.renew_before => return Ok(false),
+            Some(remaining) => self.logger.log(
+                LogLevel::Info,
+                &format!(
+                    "Certificate for {} expires in {} days, renewing",
+                    self.config.domains.join(", "),
+                    remaining.as_secs() / 86_400
+                ),
+            ),
+            None => self.logger.log(
+                LogLevel::Info,
+                &format!(
+                    "Requesting a certificate for {} from {}",
+                    self.config.domains.join(", "),
+                    self.config.directory_url
+                ),
+            ),
+        }
+
+        self.issue().await?;
+        self.logger.log(
+            LogLevel::Info,
+            &format!(
+                "Stored certificate in {}",
+                self.config.cert_path().display()
+            ),
+        );
+        Ok(true)
+    }
+
+    /// Checks on the certificate every `check_interval`, swapping renewed
+    /// ones into `acceptor`. Failures are logged and retried next time.
+    /// Stops once `shutdown` is triggered.
+    pub fn spawn_renewal(self: Arc<Self>, acceptor: Arc<ReloadableAcceptor>, shutdown: Shutdown) {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.config.check_interval) => {}
+                    _ = shutdown.triggered() => return,
+                }
+                let result = match self.ensure_certificate().await {
+                    Ok(true) => acceptor.reload(),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    self.logger.log(
+                        LogLevel::Error,
+                        &format!("Certificate renewal failed: {}", e),
+                    );
+                }
+            }
+        });
+    }
+
+    /// Time left on the stored certificate, or `None` if there isn't a
+    /// readable one.
+    fn expires_in(&self) -> Option<Duration> {
+        let chain = load_certs(&self.config.cert_path()).ok()?;
+        let (_, cert) = X509Certificate::from_der(chain.first()?).ok()?;
+        let not_after = cert.validity().not_after.timestamp();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        Some(Duration::from_secs(
+            not_after.saturating_sub(now).max(0) as u64
+        ))
+    }
+
+    async fn issue(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.config.storage_dir)?;
+        let http = HttpsClient::new(&self.config.ca_bundle)?;
+        let key = self.account_key()?;
+
+        let mut client = AcmeClient::connect(
+            http,
+            &self.config.directory_url,
+            key,
+            self.challenges.clone(),
+        )
+        .await?;
+        client.register(&self.config.contact).await?;
+        let issued = client.issue(&self.config.domains).await?;
+
+        // The key goes first so a watcher never pairs a new certificate with
+        // the old key.
+        write_private(&self.config.key_path(), issued.key_pem.as_bytes())?;
+        write_private(&self.config.cert_path(), issued.cert_pem.as_bytes())
+    }
+
+    /// Loads the account key, creating one on first use.
+    fn account_key(&self) -> io::Result<AccountKey> {
+        let path = self.config.account_key_path();
+        if path.exists() {
+            let key = load_key(&path)?;
+            return AccountKey::from_pkcs8(key.secret_der());
+        }
+        let key = AccountKey::generate()?;
+        write_private(&path, key.to_pem().as_bytes())?;
+        Ok(key)
+    }
+}
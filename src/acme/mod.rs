@@ -0,0 +1,16 @@
+mod challenges;
+mod client;
+mod config;
+mod http;
+mod jws;
+mod manager;
+
+pub use challenges::{is_challenge_path, Challenges, CHALLENGE_PATH};
+pub use config::{AcmeConfig, LETS_ENCRYPT_PRODUCTION, LETS_ENCRYPT_STAGING};
+pub use manager::CertificateManager;
+
+use std::io;
+
+fn acme_error(error: impl ToString) -> io::Error {
+    io::Error::other(error.to_string())
+}
@@ -1,11 +1,52 @@
+use crate::acme::AcmeConfig;
+use crate::limit::OverloadPolicy;
+use crate::listener::Bind;
 use crate::logger::{LogLevel, Logger};
-use std::env;
+use crate::tls::{Hsts, HttpsRedirect, TlsConfig, TlsVersion};
+use crate::websocket::DeflateConfig;
+use std::{env, time::Duration};
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Config {
     pub host: String,
     pub port: u16,
+    /// Where to accept connections, replacing `host` and `port` unless
+    /// empty.
+    pub binds: Vec<Bind>,
     pub max_request_size: usize,
+    pub max_requests_per_connection: usize,
+    /// Most connections served at once across all listeners, or `None` for
+    /// no limit.
+    pub max_connections: Option<usize>,
+    /// What happens to clients over `max_connections`.
+    pub overload_policy: OverloadPolicy,
+    /// `permessage-deflate` settings for WebSocket connections, or `None` to
+    /// never compress them.
+    pub websocket_deflate: Option<DeflateConfig>,
+    /// Serve HTTPS with this certificate instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// Obtain and renew the TLS certificate through ACME. Enables TLS even
+    /// without `tls`, and replaces its default certificate.
+    ///
+    /// Challenges are answered under `/.well-known/acme-challenge/` on the
+    /// plain HTTP listeners only, ahead of any redirect. No route is added
+    /// to the application, so the HTTPS listeners never serve them.
+    pub acme: Option<AcmeConfig>,
+    /// Redirect plain HTTP requests to HTTPS.
+    pub https_redirect: Option<HttpsRedirect>,
+    /// Send `Strict-Transport-Security` on HTTPS responses.
+    pub hsts: Option<Hsts>,
+    /// How long a client gets to send the request line and headers, counted
+    /// from its first byte. Also bounds the TLS handshake.
+    pub header_read_timeout: Duration,
+    /// How long a client gets to send the body once the headers are in.
+    pub body_read_timeout: Duration,
+    /// How long a keep-alive connection may sit idle between requests.
+    pub idle_timeout: Duration,
+    /// How long a single write to the client may take.
+    pub write_timeout: Duration,
+    /// How long open connections get to finish once shutdown begins.
+    pub shutdown_grace_period: Duration,
 }
 
 impl Default for Config {
@@ -13,7 +54,21 @@ impl Default for Config {
         Self {
             host: "127.0.0.1".to_string(),
             port: 8080,
+            binds: Vec::new(),
             max_request_size: 1024 * 1024,
+            max_requests_per_connection: 100,
+            max_connections: None,
+            overload_policy: OverloadPolicy::Wait,
+            websocket_deflate: Some(DeflateConfig::default()),
+            tls: None,
+            acme: None,
+            https_redirect: None,
+            hsts: None,
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            write_timeout: Duration::from_secs(30),
+            shutdown_grace_period: Duration::from_secs(30),
         }
     }
 }
@@ -22,7 +77,21 @@ impl Default for Config {
 pub struct ConfigBuilder {
     host: Option<String>,
     port: Option<u16>,
+    binds: Vec<Bind>,
     max_request_size: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    max_connections: Option<usize>,
+    overload_policy: Option<OverloadPolicy>,
+    websocket_deflate: Option<Option<DeflateConfig>>,
+    tls: Option<TlsConfig>,
+    acme: Option<AcmeConfig>,
+    https_redirect: Option<HttpsRedirect>,
+    hsts: Option<Hsts>,
+    header_read_timeout: Option<Duration>,
+    body_read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    shutdown_grace_period: Option<Duration>,
 }
 
 impl ConfigBuilder {
@@ -40,17 +109,109 @@ impl ConfigBuilder {
         self
     }
 
+    /// Adds a place to accept connections. Once any is added, `host` and
+    /// `port` are no longer bound.
+    pub fn bind(mut self, bind: Bind) -> Self {
+        self.binds.push(bind);
+        self
+    }
+
     pub fn max_request_size(mut self, size: usize) -> Self {
         self.max_request_size = Some(size);
         self
     }
 
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.max_requests_per_connection = Some(max);
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.overload_policy = Some(policy);
+        self
+    }
+
+    pub fn websocket_deflate(mut self, deflate: Option<DeflateConfig>) -> Self {
+        self.websocket_deflate = Some(deflate);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn acme(mut self, acme: AcmeConfig) -> Self {
+        self.acme = Some(acme);
+        self
+    }
+
+    pub fn https_redirect(mut self, redirect: HttpsRedirect) -> Self {
+        self.https_redirect = Some(redirect);
+        self
+    }
+
+    pub fn hsts(mut self, hsts: Hsts) -> Self {
+        self.hsts = Some(hsts);
+        self
+    }
+
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn body_read_timeout(mut self, timeout: Duration) -> Self {
+        self.body_read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    pub fn shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = Some(grace_period);
+        self
+    }
+
     pub fn build(self) -> Config {
         let default = Config::default();
         Config {
             host: self.host.unwrap_or(default.host),
             port: self.port.unwrap_or(default.port),
+            binds: self.binds,
             max_request_size: self.max_request_size.unwrap_or(default.max_request_size),
+            max_requests_per_connection: self
+                .max_requests_per_connection
+                .unwrap_or(default.max_requests_per_connection),
+            max_connections: self.max_connections.or(default.max_connections),
+            overload_policy: self.overload_policy.unwrap_or(default.overload_policy),
+            websocket_deflate: self.websocket_deflate.unwrap_or(default.websocket_deflate),
+            tls: self.tls.or(default.tls),
+            acme: self.acme.or(default.acme),
+            https_redirect: self.https_redirect.or(default.https_redirect),
+            hsts: self.hsts.or(default.hsts),
+            header_read_timeout: self
+                .header_read_timeout
+                .unwrap_or(default.header_read_timeout),
+            body_read_timeout: self.body_read_timeout.unwrap_or(default.body_read_timeout),
+            idle_timeout: self.idle_timeout.unwrap_or(default.idle_timeout),
+            write_timeout: self.write_timeout.unwrap_or(default.write_timeout),
+            shutdown_grace_period: self
+                .shutdown_grace_period
+                .unwrap_or(default.shutdown_grace_period),
         }
     }
 }
@@ -64,14 +225,199 @@ impl From<ConfigBuilder> for Config {
 impl Config {
     pub fn from_env() -> Self {
         let validator = EnvValidator::new(Logger::new());
+        let default = Config::default();
         Self {
             host: validator.get_var("HOST", "a string (e.g., '127.0.0.1')"),
             port: validator.get_var_parse("PORT", "a number between 0-65535"),
+            binds: Self::binds_from_env(&validator),
             max_request_size: validator.get_var_parse(
                 "MAX_REQUEST_SIZE",
                 "a number in bytes (e.g., 1048576 for 1MB)",
             ),
+            max_requests_per_connection: validator.get_var_parse_or(
+                "MAX_REQUESTS_PER_CONNECTION",
+                "a number of requests (e.g., 100)",
+                default.max_requests_per_connection,
+            ),
+            max_connections: validator
+                .get_var_opt("MAX_CONNECTIONS")
+                .map(|_| validator.get_var_parse("MAX_CONNECTIONS", "a number of connections")),
+            overload_policy: Self::overload_policy_from_env(&validator),
+            websocket_deflate: validator
+                .get_var_parse_or("WS_DEFLATE", "true or false", true)
+                .then(DeflateConfig::default),
+            tls: Self::tls_from_env(&validator),
+            acme: Self::acme_from_env(&validator),
+            https_redirect: Self::https_redirect_from_env(&validator),
+            hsts: Self::hsts_from_env(&validator),
+            header_read_timeout: Self::timeout_from_env(
+                &validator,
+                "HEADER_READ_TIMEOUT",
+                default.header_read_timeout,
+            ),
+            body_read_timeout: Self::timeout_from_env(
+                &validator,
+                "BODY_READ_TIMEOUT",
+                default.body_read_timeout,
+            ),
+            idle_timeout: Self::timeout_from_env(&validator, "IDLE_TIMEOUT", default.idle_timeout),
+            write_timeout: Self::timeout_from_env(
+                &validator,
+                "WRITE_TIMEOUT",
+                default.write_timeout,
+            ),
+            shutdown_grace_period: Self::timeout_from_env(
+                &validator,
+                "SHUTDOWN_GRACE_PERIOD",
+                default.shutdown_grace_period,
+            ),
+        }
+    }
+
+    /// `BIND` lists addresses like `0.0.0.0:8080`, `[::]:8080` or
+    /// `unix:/run/app.sock`, comma separated. `UNIX_SOCKET_MODE` sets the
+    /// octal permissions of the Unix sockets.
+    fn binds_from_env(validator: &EnvValidator) -> Vec<Bind> {
+        let binds = match validator.get_var_opt("BIND") {
+            Some(binds) => binds,
+            None => return Vec::new(),
+        };
+        let mode = validator.get_var_opt("UNIX_SOCKET_MODE").map(|mode| {
+            u32::from_str_radix(&mode, 8).unwrap_or_else(|_| {
+                validator.error("UNIX_SOCKET_MODE", "octal permissions (e.g., 660)")
+            })
+        });
+        binds
+            .split(',')
+            .map(str::trim)
+            .filter(|bind| !bind.is_empty())
+            .map(|bind| match bind.parse() {
+                Ok(Bind::Unix { path, .. }) => Bind::Unix { path, mode },
+                Ok(bind) => bind,
+                Err(_) => {
+                    validator.error("BIND", "a comma separated list of host:port or unix:/path")
+                }
+            })
+            .collect()
+    }
+
+    /// `OVERLOAD_POLICY` is `wait` or `reject`, with rejected clients told to
+    /// retry after `OVERLOAD_RETRY_AFTER` seconds.
+    fn overload_policy_from_env(validator: &EnvValidator) -> OverloadPolicy {
+        match validator.get_var_parse_or("OVERLOAD_POLICY", "wait or reject", OverloadPolicy::Wait)
+        {
+            OverloadPolicy::Reject { retry_after } => OverloadPolicy::Reject {
+                retry_after: Self::timeout_from_env(validator, "OVERLOAD_RETRY_AFTER", retry_after),
+            },
+            policy => policy,
+        }
+    }
+
+    fn timeout_from_env(validator: &EnvValidator, key: &str, default: Duration) -> Duration {
+        Duration::from_secs(validator.get_var_parse_or(
+            key,
+            "a number of seconds",
+            default.as_secs(),
+        ))
+    }
+
+    /// `HTTPS_REDIRECT_PORT` names the plain port to redirect from, or 0 to
+    /// only redirect plain requests sent to the HTTPS port.
+    fn https_redirect_from_env(validator: &EnvValidator) -> Option<HttpsRedirect> {
+        validator.get_var_opt("HTTPS_REDIRECT_PORT")?;
+        let port: u16 = validator.get_var_parse(
+            "HTTPS_REDIRECT_PORT",
+            "a port number, 0 for the HTTPS port only",
+        );
+        Some(HttpsRedirect {
+            port: (port > 0).then_some(port),
+            ..HttpsRedirect::default()
+        })
+    }
+
+    fn hsts_from_env(validator: &EnvValidator) -> Option<Hsts> {
+        let max_age: u64 =
+            validator.get_var_parse_or("HSTS_MAX_AGE", "a number of seconds, 0 to disable", 0);
+        (max_age > 0).then(|| Hsts {
+            max_age: Duration::from_secs(max_age),
+            ..Hsts::default()
+        })
+    }
+
+    /// ACME is on when `ACME_DOMAINS` is set.
+    fn acme_from_env(validator: &EnvValidator) -> Option<AcmeConfig> {
+        let domains = validator.get_var_opt("ACME_DOMAINS")?;
+        let mut acme = AcmeConfig::new(domains.split(',').map(str::trim).filter(|d| !d.is_empty()));
+        if let Some(email) = validator.get_var_opt("ACME_EMAIL") {
+            acme = acme.contact(email);
+        }
+        if let Some(url) = validator.get_var_opt("ACME_DIRECTORY_URL") {
+            acme = acme.directory_url(url);
+        }
+        if let Some(path) = validator.get_var_opt("ACME_CA_BUNDLE") {
+            acme = acme.ca_bundle(path);
+        }
+        if let Some(path) = validator.get_var_opt("ACME_STORAGE_DIR") {
+            acme = acme.storage_dir(path);
+        }
+        let http_port = validator.get_var_parse_or("ACME_HTTP_PORT", "a port number", 80);
+        Some(acme.http_port(http_port))
+    }
+
+    /// TLS is on when `TLS_CERT_PATH` is set, which then needs
+    /// `TLS_KEY_PATH`, or when `TLS_ENABLED` is true in development.
+    fn tls_from_env(validator: &EnvValidator) -> Option<TlsConfig> {
+        let tls = match validator.get_var_opt("TLS_CERT_PATH") {
+            Some(cert_path) => TlsConfig::new(
+                cert_path,
+                validator.get_var("TLS_KEY_PATH", "a path to a PEM private key"),
+            ),
+            None if validator.get_var_parse_or("TLS_ENABLED", "true or false", false) => {
+                TlsConfig::development()
+            }
+            None => return None,
+        };
+        let mut tls = tls.min_version(validator.get_var_parse_or(
+            "TLS_MIN_VERSION",
+            "1.2 or 1.3",
+            TlsVersion::Tls12,
+        ));
+        if let Some(suites) = validator.get_var_opt("TLS_CIPHER_SUITES") {
+            tls = tls.cipher_suites(suites.split(',').map(str::trim).filter(|s| !s.is_empty()));
+        }
+        if let Some(ca_path) = validator.get_var_opt("TLS_CLIENT_CA_PATH") {
+            let mode = validator.get_var_opt("TLS_CLIENT_AUTH");
+            tls = match mode.as_deref() {
+                None | Some("require") => tls.require_client_cert(ca_path),
+                Some("request") => tls.request_client_cert(ca_path),
+                Some(_) => validator.error("TLS_CLIENT_AUTH", "require or request"),
+            };
+        }
+        if let Some(dir) = validator.get_var_opt("TLS_DEV_CERT_DIR") {
+            tls = tls.dev_cert_dir(dir);
         }
+        let reload_secs = validator.get_var_parse_or(
+            "TLS_RELOAD_INTERVAL",
+            "a number of seconds, 0 to only reload on SIGHUP",
+            30,
+        );
+        tls = tls.reload_interval((reload_secs > 0).then(|| Duration::from_secs(reload_secs)));
+        // Entries look like `api.example.com:/path/cert.pem:/path/key.pem`.
+        for entry in validator
+            .get_var_opt("TLS_SNI_CERTS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|e| !e.trim().is_empty())
+        {
+            let type_info = "a comma separated list of hostname:cert_path:key_path";
+            match entry.trim().splitn(3, ':').collect::<Vec<_>>()[..] {
+                [hostname, cert_path, key_path] => {
+                    tls = tls.certificate(hostname, cert_path, key_path)
+                }
+                _ => validator.error("TLS_SNI_CERTS", type_info),
+            }
+        }
+        Some(tls)
     }
 }
 
@@ -95,9 +441,25 @@ impl EnvValidator {
         env::var(key).unwrap_or_else(|_| self.error(key, type_info))
     }
 
+    pub fn get_var_opt(&self, key: &str) -> Option<String> {
+        env::var(key).ok().filter(|value| !value.is_empty())
+    }
+
     pub fn get_var_parse<T: std::str::FromStr>(&self, key: &str, type_info: &str) -> T {
         self.get_var(key, type_info)
             .parse()
             .unwrap_or_else(|_| self.error(key, type_info))
     }
+
+    pub fn get_var_parse_or<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        type_info: &str,
+        default: T,
+    ) -> T {
+        match env::var(key) {
+            Ok(value) => value.parse().unwrap_or_else(|_| self.error(key, type_info)),
+            Err(_) => default,
+        }
+    }
 }
@@ -1,36 +1,96 @@
-use crate::http::{HttpHandler, HttpMethod, RequestResponse};
+use crate::acme::{self, Challenges};
+use crate::config::Config;
+use crate::http::{
+    encode_chunk, FrameError, HttpHandler, HttpRequest, HttpVersion, RequestDecoder, RequestFrame,
+    RequestResponse, Res, ResponseBuilder, LAST_CHUNK,
+};
+use crate::http2::{self, frame::ErrorCode, Http2Connection};
 use crate::logger::{LogLevel, Logger};
+use crate::shutdown::Shutdown;
+use crate::stream::ClientStream;
+use crate::tls::{self, TlsInfo};
 
 use bytes::BytesMut;
+use std::future::Future;
 use std::io;
-use std::str::FromStr;
 use std::sync::Arc;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
-};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::time::{self, Instant};
 
 #[derive(Debug)]
 pub enum Protocol {
     Http1,
+    Tls,
     WebSocket,
     Http2,
     Unknown,
 }
 
+/// What a connection does with the requests it reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Routing {
+    /// Hands them to the application's routes.
+    #[default]
+    App,
+    /// Redirects them to the HTTPS port, apart from ACME challenges which
+    /// have to be answered over plain HTTP.
+    RedirectToHttps,
+    /// Answers ACME challenges and nothing else.
+    AcmeChallenges,
+}
+
+/// What a connection is waiting on the client for, which decides how long
+/// it may take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadPhase {
+    /// Nothing of the next request has arrived yet.
+    Idle,
+    Headers,
+    Body,
+}
+
+impl ReadPhase {
+    fn timeout(self, config: &Config) -> Duration {
+        match self {
+            ReadPhase::Idle => config.idle_timeout,
+            ReadPhase::Headers => config.header_read_timeout,
+            ReadPhase::Body => config.body_read_timeout,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Connection {
-    stream: BufWriter<TcpStream>,
+    stream: BufWriter<ClientStream>,
 
     buffer: BytesMut,
 
+    /// Progress on the request at the front of `buffer`.
+    decoder: RequestDecoder,
+
     logger: Logger,
 
     http_handler: Arc<HttpHandler>,
+
+    config: Arc<Config>,
+
+    tls: Option<Arc<TlsInfo>>,
+
+    routing: Routing,
+
+    challenges: Challenges,
+
+    shutdown: Shutdown,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream, http_handler: Arc<HttpHandler>) -> Result<Self, io::Error> {
+    pub fn new(
+        stream: ClientStream,
+        http_handler: Arc<HttpHandler>,
+        config: Arc<Config>,
+    ) -> Result<Self, io::Error> {
+        let tls = stream.tls_info().map(Arc::new);
         let stream = BufWriter::new(stream);
         let buffer = BytesMut::with_capacity(1024 * 1024);
         let logger = Logger::new();
@@ -38,22 +98,110 @@ impl Connection {
         Ok(Self {
             stream,
             buffer,
+            decoder: RequestDecoder::default(),
             logger,
             http_handler,
+            config,
+            tls,
+            routing: Routing::App,
+            challenges: Challenges::default(),
+            shutdown: Shutdown::new(),
         })
     }
 
-    pub async fn process(mut self) -> io::Result<()> {
-        if 0 == self.stream.read_buf(&mut self.buffer).await? {
-            self.logger.log(LogLevel::Application, "Connection closed");
-            return Ok(());
+    /// Closes the connection between requests once `shutdown` is triggered,
+    /// instead of keeping it alive for another one.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Records the configured hostname whose certificate answered the TLS
+    /// handshake as `TlsInfo::server_name`.
+    pub fn virtual_host(mut self, hostname: Option<String>) -> Self {
+        if let Some(tls) = self.tls.as_mut().and_then(Arc::get_mut) {
+            tls.server_name = hostname;
         }
+        self
+    }
+
+    pub fn routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Pending ACME challenges answered when routing isn't `Routing::App`.
+    pub fn acme_challenges(mut self, challenges: Challenges) -> Self {
+        self.challenges = challenges;
+        self
+    }
 
-        let first_bytes = self.peek(7);
+    pub async fn process(mut self) -> io::Result<()> {
+        let protocol = match self.negotiated_protocol() {
+            Some(protocol) => protocol,
+            None => {
+                let read = self.stream.read_buf(&mut self.buffer);
+                let read = tokio::select! {
+                    read = time::timeout(self.config.header_read_timeout, read) => read,
+                    // Nothing has been asked yet, so there's nothing to finish.
+                    _ = self.shutdown.triggered() => return Ok(()),
+                };
+                match read {
+                    Ok(Ok(0)) => {
+                        self.logger.log(LogLevel::Application, "Connection closed");
+                        return Ok(());
+                    }
+                    Ok(read) => {
+                        read?;
+                    }
+                    Err(_) => {
+                        self.log_timeout("waiting for a request");
+                        return Ok(());
+                    }
+                }
+                self.detect_protocol(self.peek(8))
+            }
+        };
+        let first_bytes = self.peek(8);
 
-        match self.detect_protocol(first_bytes) {
-            Protocol::Http1 => {
-                self.handle_http().await?;
+        match protocol {
+            Protocol::Http1 => match self.serve_http().await {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    self.log_timeout("writing the response")
+                }
+                result => result?,
+            },
+            Protocol::Http2 => {
+                let ip = self.stream.get_ref().peer_addr()?.to_string();
+                let connection = Http2Connection::new(
+                    self.stream,
+                    self.buffer,
+                    self.http_handler,
+                    self.config,
+                    ip.clone(),
+                    self.tls,
+                )
+                .with_shutdown(self.shutdown);
+                let served = match self.routing {
+                    Routing::App => connection.serve().await,
+                    // Redirects and challenges are only answered over
+                    // HTTP/1.1, so the client has to retry with that.
+                    _ => connection.refuse(ErrorCode::Http11Required).await,
+                };
+                match served {
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => self.logger.log(
+                        LogLevel::Warning,
+                        &format!("Timed out writing to {}, closing the connection", ip),
+                    ),
+                    result => result?,
+                }
+            }
+            Protocol::Tls => {
+                let ip = self.stream.get_ref().peer_addr()?;
+                self.logger.log(
+                    LogLevel::Warning,
+                    &format!("TLS handshake from {} on a plain HTTP port", ip),
+                );
             }
             Protocol::Unknown => self.logger.log(
                 LogLevel::Application,
@@ -71,44 +219,330 @@ impl Connection {
         Ok(())
     }
 
-    pub async fn handle_http(&mut self) -> io::Result<()> {
+    /// Serves requests on the connection until either side asks to close it
+    /// or `max_requests_per_connection` is reached.
+    ///
+    /// Pipelined requests already sitting in the buffer are handled one after
+    /// another without another read, so responses go out in request order.
+    /// Their responses are only flushed once the buffer runs dry.
+    async fn serve_http(&mut self) -> io::Result<()> {
+        let mut served = 0;
+        while let Some(frame) = self.read_request().await? {
+            served += 1;
+            let allow_keep_alive = served < self.config.max_requests_per_connection;
+            let raw = self.buffer.split_to(frame.len);
+            let keep_alive = self.handle_http(frame, &raw, allow_keep_alive).await?;
+
+            if !keep_alive {
+                break;
+            }
+        }
+        within(self.config.write_timeout, self.stream.flush()).await
+    }
+
+    /// Reads until a complete request is buffered. Returns `None` if the peer
+    /// closed the connection or the request was rejected.
+    ///
+    /// Each phase of the request has its own deadline, so a client trickling
+    /// bytes can't hold the connection open for longer than that. Clients
+    /// that go quiet mid-request get a 408, idle ones are closed silently.
+    async fn read_request(&mut self) -> io::Result<Option<RequestFrame>> {
+        let mut waiting: Option<(ReadPhase, Instant)> = None;
+        loop {
+            match self
+                .decoder
+                .decode(&self.buffer, self.config.max_request_size)
+            {
+                Ok(Some(frame)) => return Ok(Some(frame)),
+                Ok(None) => {}
+                Err(e) => {
+                    let ip = self.stream.get_ref().peer_addr()?;
+                    self.logger.log(
+                        LogLevel::Warning,
+                        &format!("Rejected request from {}: {:?}", ip, e),
+                    );
+                    self.reject(e).await?;
+                    return Ok(None);
+                }
+            }
+
+            // Nothing complete left to answer, so send what's been written
+            // before waiting on the client.
+            within(self.config.write_timeout, self.stream.flush()).await?;
+
+            let phase = self.read_phase();
+            let deadline = match waiting {
+                Some((waiting_on, deadline)) if waiting_on == phase => deadline,
+                _ => Instant::now() + phase.timeout(&self.config),
+            };
+            waiting = Some((phase, deadline));
+
+            let read = self.stream.read_buf(&mut self.buffer);
+            let result = tokio::select! {
+                result = time::timeout_at(deadline, read) => result,
+                // Idle connections are closed right away on shutdown.
+                _ = self.shutdown.triggered(), if phase == ReadPhase::Idle => return Ok(None),
+            };
+            match result {
+                Ok(Ok(0)) => return Ok(None),
+                Ok(read) => {
+                    read?;
+                }
+                Err(_) if phase == ReadPhase::Idle => {
+                    self.log_timeout("waiting for the next request");
+                    return Ok(None);
+                }
+                Err(_) => {
+                    self.log_timeout(match phase {
+                        ReadPhase::Body => "reading the request body",
+                        _ => "reading the request headers",
+                    });
+                    self.reject(FrameError::TimedOut).await?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// How far the client has got with the request at the front of the
+    /// buffer.
+    fn read_phase(&self) -> ReadPhase {
+        if self.buffer.is_empty() {
+            ReadPhase::Idle
+        } else if self.decoder.has_head() {
+            ReadPhase::Body
+        } else {
+            ReadPhase::Headers
+        }
+    }
+
+    /// Answers a request that can't be served and is closing the connection.
+    async fn reject(&mut self, error: FrameError) -> io::Result<()> {
+        let write_timeout = self.config.write_timeout;
+        within(write_timeout, self.stream.write_all(&error.response())).await?;
+        within(write_timeout, self.stream.flush()).await
+    }
+
+    fn log_timeout(&self, waiting_on: &str) {
+        let ip = match self.stream.get_ref().peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown peer".to_string(),
+        };
+        self.logger.log(
+            LogLevel::Warning,
+            &format!(
+                "Timed out {} from {}, closing the connection",
+                waiting_on, ip
+            ),
+        );
+    }
+
+    /// Handles the request framed in `raw` and returns whether the connection
+    /// should be kept open for another one.
+    ///
+    /// A request asking for `Upgrade: h2c` is answered over HTTP/2 instead,
+    /// and the connection stays with HTTP/2 until it closes.
+    pub async fn handle_http(
+        &mut self,
+        frame: RequestFrame,
+        raw: &[u8],
+        allow_keep_alive: bool,
+    ) -> io::Result<bool> {
         let start_time = std::time::Instant::now();
 
         let ip = self.stream.get_ref().peer_addr()?.to_string();
 
-        let request_line = std::str::from_utf8(&self.buffer)
-            .ok()
-            .and_then(|s| s.lines().next())
-            .unwrap_or("");
+        let request = match frame.into_request(raw) {
+            Some(request) => request,
+            None => {
+                self.reject(FrameError::Malformed).await?;
+                return Ok(false);
+            }
+        };
+
+        // h2c is only for cleartext; TLS clients negotiate h2 through ALPN.
+        let upgrade = match (&self.tls, self.routing) {
+            (None, Routing::App) => http2::upgrade_settings(&request),
+            _ => None,
+        };
+        if let Some(settings) = upgrade {
+            self.upgrade_h2c(request, &settings, ip).await?;
+            return Ok(false);
+        }
+
+        let method = request.method;
+        let path = request.path.clone();
+        let version = request.version;
+        let mut keep_alive =
+            allow_keep_alive && request.keep_alive() && !self.shutdown.is_triggered();
 
-        let mut parts = request_line.split_whitespace();
-        let method = parts
-            .next()
-            .map(|s| HttpMethod::from_str(s).unwrap_or(HttpMethod::Unknown))
-            .unwrap_or(HttpMethod::Unknown);
+        let mut response = match self.routing {
+            Routing::App => self.http_handler.handle_request(request, self.tls.clone()),
+            _ if acme::is_challenge_path(&path) => self.challenges.response(&path),
+            Routing::RedirectToHttps => self.config.https_redirect.unwrap_or_default().response(
+                &request,
+                self.config.port,
+                &self.config.host,
+            ),
+            Routing::AcmeChallenges => {
+                Res::new(ResponseBuilder::not_found().text("Not Found").build(), 404)
+            }
+        };
+        if let (Some(_), Some(hsts)) = (&self.tls, self.config.hsts) {
+            response.set_header("Strict-Transport-Security", &hsts.header_value());
+        }
+        if let Some(mut upgrade) = response.websocket.take() {
+            let extensions = self
+                .config
+                .websocket_deflate
+                .and_then(|deflate| upgrade.negotiate_deflate(&deflate));
+            if let Some(extensions) = extensions {
+                response.set_header("Sec-WebSocket-Extensions", &extensions);
+            }
+            Logger::log_http(&RequestResponse {
+                method,
+                path,
+                ip: ip.clone(),
+                server_name: self.server_name(),
+                alpn_protocol: self.alpn_protocol(),
+                status: response.status,
+                duration: start_time.elapsed(),
+            });
+            let write_timeout = self.config.write_timeout;
+            within(write_timeout, self.stream.write_all(&response.buffer)).await?;
+            within(write_timeout, self.stream.flush()).await?;
 
-        let path = parts.next().unwrap_or("/").to_string();
+            let buffer = std::mem::take(&mut self.buffer);
+            upgrade
+                .serve(
+                    &mut self.stream,
+                    buffer,
+                    self.config.max_request_size,
+                    write_timeout,
+                    self.shutdown.clone(),
+                    ip,
+                )
+                .await?;
+            return Ok(false);
+        }
 
-        let response = self.http_handler.handle(&self.buffer);
+        if response.stream.is_some() && version == HttpVersion::Http10 {
+            // HTTP/1.0 clients can't decode chunked bodies, so the body is
+            // sent raw and its end is signalled by closing the connection.
+            response.remove_header("Transfer-Encoding");
+            keep_alive = false;
+        }
+        response.set_header(
+            "Connection",
+            if keep_alive { "keep-alive" } else { "close" },
+        );
         let duration = start_time.elapsed();
 
         Logger::log_http(&RequestResponse {
             method,
             path,
             ip,
+            server_name: self.server_name(),
+            alpn_protocol: self.alpn_protocol(),
             status: response.status,
             duration,
         });
 
-        self.stream.write_all(&response.buffer).await?;
-        self.stream.flush().await
+        self.write_response(response, version == HttpVersion::Http11)
+            .await?;
+
+        Ok(keep_alive)
+    }
+
+    /// Switches the connection to HTTP/2, answering `request` as stream 1.
+    /// Anything already buffered after the request belongs to HTTP/2.
+    async fn upgrade_h2c(
+        &mut self,
+        mut request: HttpRequest,
+        settings: &[u8],
+        ip: String,
+    ) -> io::Result<()> {
+        let response = ResponseBuilder::new()
+            .status(ResponseBuilder::SWITCHING_PROTOCOLS)
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "h2c")
+            .build();
+        within(self.config.write_timeout, self.stream.write_all(&response)).await?;
+
+        request.version = HttpVersion::Http2;
+        let buffer = std::mem::take(&mut self.buffer);
+        Http2Connection::new(
+            &mut self.stream,
+            buffer,
+            Arc::clone(&self.http_handler),
+            Arc::clone(&self.config),
+            ip,
+            self.tls.clone(),
+        )
+        .with_shutdown(self.shutdown.clone())
+        .serve_upgrade(request, settings)
+        .await
+    }
+
+    fn server_name(&self) -> Option<String> {
+        self.tls.as_ref().and_then(|tls| tls.server_name.clone())
+    }
+
+    fn alpn_protocol(&self) -> Option<String> {
+        self.tls.as_ref().and_then(|tls| tls.alpn_protocol.clone())
+    }
+
+    /// Writes `response`, streaming its body if it has one. Every write is
+    /// bounded by `write_timeout` rather than the response as a whole, so
+    /// long-lived streams keep going as long as the client keeps reading.
+    async fn write_response(&mut self, response: Res, chunked: bool) -> io::Result<()> {
+        let write_timeout = self.config.write_timeout;
+        within(write_timeout, self.stream.write_all(&response.buffer)).await?;
+
+        if let Some(mut body) = response.stream {
+            if body.is_live() {
+                within(write_timeout, self.stream.flush()).await?;
+            }
+            while let Some(chunk) = body.next_chunk().await {
+                if chunk.is_empty() {
+                    continue;
+                }
+                if chunked {
+                    within(write_timeout, self.stream.write_all(&encode_chunk(&chunk))).await?;
+                } else {
+                    within(write_timeout, self.stream.write_all(&chunk)).await?;
+                }
+                if body.is_live() {
+                    within(write_timeout, self.stream.flush()).await?;
+                }
+            }
+            if chunked {
+                within(write_timeout, self.stream.write_all(LAST_CHUNK)).await?;
+            }
+        }
+
+        Ok(())
     }
 
     fn peek(&self, n: usize) -> &[u8] {
         &self.buffer[..std::cmp::min(n, self.buffer.len())]
     }
 
+    /// The protocol picked through ALPN during the TLS handshake. Clients
+    /// that didn't offer ALPN fall back to sniffing the first bytes.
+    fn negotiated_protocol(&self) -> Option<Protocol> {
+        match self.tls.as_ref()?.alpn_protocol.as_deref()? {
+            tls::ALPN_H2 => Some(Protocol::Http2),
+            tls::ALPN_HTTP11 => Some(Protocol::Http1),
+            _ => None,
+        }
+    }
+
     fn detect_protocol(&self, bytes: &[u8]) -> Protocol {
+        // A TLS handshake record, as sent by HTTPS clients.
+        if bytes.starts_with(&[0x16, 0x03]) {
+            return Protocol::Tls;
+        }
         if bytes.len() < 4 {
             return Protocol::Unknown;
         }
@@ -116,7 +550,8 @@ impl Connection {
         if let Some(space_pos) = bytes.iter().position(|&b| b == b' ') {
             let method = &bytes[..space_pos];
             match method {
-                b"GET" | b"POST" | b"PUT" | b"HEAD" | b"DELETE" | b"PATCH" => Protocol::Http1,
+                b"GET" | b"POST" | b"PUT" | b"HEAD" | b"DELETE" | b"PATCH" | b"OPTIONS"
+                | b"CONNECT" | b"TRACE" => Protocol::Http1,
                 b"PRI" => Protocol::Http2,
                 _ => Protocol::Unknown,
             }
@@ -125,3 +560,80 @@ impl Connection {
         }
     }
 }
+
+/// Runs an I/O operation, failing with `TimedOut` if it takes longer than
+/// `limit`.
+pub(crate) async fn within<T>(
+    limit: Duration,
+    io: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match time::timeout(limit, io).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "client stopped reading",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{MiddlewareHandler, RouteManager};
+    use crate::http2::{
+        frame::{Frame, FrameType},
+        PREFACE,
+    };
+    use std::collections::HashMap;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn upgraded_h2c_connection_goes_away_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let handler = HttpHandler::new(
+            Arc::new(RouteManager::new()),
+            Arc::new(MiddlewareHandler::new()),
+            Arc::new(HashMap::new()),
+        );
+        let shutdown = Shutdown::new();
+        let connection = Connection::new(
+            ClientStream::from(socket),
+            Arc::new(handler),
+            Arc::new(Config::default()),
+        )
+        .unwrap()
+        .with_shutdown(shutdown.clone());
+        tokio::spawn(connection.process());
+
+        let mut out = b"GET / HTTP/1.1\r\nHost: localhost\r\n\
+            Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: \r\n\r\n"
+            .to_vec();
+        out.extend_from_slice(PREFACE);
+        Frame::settings(&[]).encode(&mut out);
+        client.write_all(&out).await.unwrap();
+
+        let mut received = Vec::new();
+        while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+            client.read_buf(&mut received).await.unwrap();
+        }
+        assert!(received.starts_with(b"HTTP/1.1 101"));
+        shutdown.trigger();
+        time::timeout(Duration::from_secs(2), client.read_to_end(&mut received))
+            .await
+            .expect("connection closed")
+            .unwrap();
+
+        let head_end = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let mut frames = &received[head_end..];
+        let mut kinds = Vec::new();
+        while let Some((frame, used)) = Frame::parse(frames, 1 << 24).unwrap() {
+            frames = &frames[used..];
+            kinds.push(frame.kind);
+        }
+        assert!(kinds.contains(&FrameType::GoAway));
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use super::FrameError;
+
+/// Chunk size lines longer than this are treated as malformed rather than
+/// buffered indefinitely.
+const MAX_CHUNK_LINE: usize = 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkedBody {
+    pub data: Vec<u8>,
+    pub trailers: HashMap<String, String>,
+    /// Number of raw bytes the encoded body occupied in the buffer.
+    pub encoded_len: usize,
+}
+
+/// Terminates a chunked body that has no trailers.
+pub const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// Frames `data` as a single chunk. `data` must not be empty, since an empty
+/// chunk marks the end of the body.
+pub fn encode_chunk(data: &[u8]) -> Vec<u8> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body as it arrives. Chunks are
+/// kept once complete, so each call only looks at what's new.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkedDecoder {
+    body: ChunkedBody,
+    /// Where the next chunk or trailer line starts.
+    pos: usize,
+    /// The last chunk has been read and trailers follow.
+    in_trailers: bool,
+}
+
+impl ChunkedDecoder {
+    /// Continues decoding the body at the start of `buffer`, which holds
+    /// what was passed last time and possibly more. Returns `Ok(None)` until
+    /// the terminating chunk and trailers have arrived. `max_size` caps the
+    /// raw encoded length.
+    pub fn decode(
+        &mut self,
+        buffer: &[u8],
+        max_size: usize,
+    ) -> Result<Option<ChunkedBody>, FrameError> {
+        while !self.in_trailers {
+            let line = match read_line(buffer, self.pos, max_size)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            let size = parse_chunk_size(&buffer[self.pos..self.pos + line])?;
+            let start = self.pos + line + 2;
+
+            if size == 0 {
+                self.pos = start;
+                self.in_trailers = true;
+                break;
+            }
+
+            // Checked before any arithmetic, since `size` comes straight from
+            // the client and can be anything up to `usize::MAX`.
+            if size > max_size.saturating_sub(start) {
+                return Err(FrameError::BodyTooLarge);
+            }
+            let chunk_end = start + size;
+            match chunk_end.checked_add(2) {
+                Some(end) if end <= max_size => {}
+                _ => return Err(FrameError::BodyTooLarge),
+            }
+            if buffer.len() < chunk_end + 2 {
+                return Ok(None);
+            }
+            if &buffer[chunk_end..chunk_end + 2] != b"\r\n" {
+                return Err(FrameError::Malformed);
+            }
+
+            self.body.data.extend_from_slice(&buffer[start..chunk_end]);
+            self.pos = chunk_end + 2;
+        }
+
+        loop {
+            let line = match read_line(buffer, self.pos, max_size)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            let field = std::str::from_utf8(&buffer[self.pos..self.pos + line])
+                .map_err(|_| FrameError::Malformed)?;
+            self.pos += line + 2;
+
+            if field.is_empty() {
+                let encoded_len = self.pos;
+                let mut body = std::mem::take(self).body;
+                body.encoded_len = encoded_len;
+                return Ok(Some(body));
+            }
+
+            let (key, value) = field.split_once(':').ok_or(FrameError::Malformed)?;
+            self.body
+                .trailers
+                .insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+}
+
+/// Finds the CRLF ending the line at `start`, returning the line length.
+fn read_line(buffer: &[u8], start: usize, max_size: usize) -> Result<Option<usize>, FrameError> {
+    match buffer[start..].windows(2).position(|w| w == b"\r\n") {
+        Some(len) => Ok(Some(len)),
+        None if buffer.len() > max_size => Err(FrameError::BodyTooLarge),
+        None if buffer.len() - start > MAX_CHUNK_LINE => Err(FrameError::Malformed),
+        None => Ok(None),
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, FrameError> {
+    let line = std::str::from_utf8(line).map_err(|_| FrameError::Malformed)?;
+    // Chunk extensions are allowed after a ';' and ignored.
+    let size = line.split(';').next().unwrap_or("").trim();
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(FrameError::Malformed);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| FrameError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_chunk_size_is_rejected_without_overflowing() {
+        let size = format!("{:x}", usize::MAX - 19);
+        let buffer = format!("{}\r\nhello\r\n0\r\n\r\n", size);
+        assert_eq!(
+            decode(buffer.as_bytes(), 1024),
+            Err(FrameError::BodyTooLarge)
+        );
+        assert_eq!(
+            decode(buffer.as_bytes(), usize::MAX),
+            Err(FrameError::BodyTooLarge)
+        );
+    }
+
+    fn decode(buffer: &[u8], max_size: usize) -> Result<Option<ChunkedBody>, FrameError> {
+        ChunkedDecoder::default().decode(buffer, max_size)
+    }
+
+    #[test]
+    fn body_split_across_reads_decodes_the_same() {
+        let encoded = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nExpires: never\r\n\r\n";
+        let whole = decode(encoded, 1024).unwrap().unwrap();
+        assert_eq!(whole.data, b"hello, world");
+        assert_eq!(whole.encoded_len, encoded.len());
+
+        let mut decoder = ChunkedDecoder::default();
+        for end in 1..encoded.len() {
+            assert_eq!(decoder.decode(&encoded[..end], 1024), Ok(None));
+        }
+        assert_eq!(decoder.decode(encoded, 1024), Ok(Some(whole)));
+    }
+
+    #[test]
+    fn trailers_follow_the_last_chunk() {
+        let encoded = b"3\r\nabc\r\n0\r\nX-Checksum: 42\r\nExpires : never \r\n\r\nGET";
+        let body = decode(encoded, 1024).unwrap().unwrap();
+        assert_eq!(body.data, b"abc");
+        assert_eq!(body.encoded_len, encoded.len() - 3);
+        assert_eq!(body.trailers.len(), 2);
+        assert_eq!(body.trailers["x-checksum"], "42");
+        assert_eq!(body.trailers["expires"], "never");
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        let long_line = format!("5;{}", "x".repeat(MAX_CHUNK_LINE));
+        let cases: [&[u8]; 8] = [
+            b"\r\n",
+            b"g\r\n",
+            b"-5\r\nhello\r\n",
+            b"+5\r\nhello\r\n",
+            b"5\r\nhelloXX0\r\n\r\n",
+            b"0\r\nno colon\r\n\r\n",
+            b"0\r\nX: \xff\r\n\r\n",
+            long_line.as_bytes(),
+        ];
+        for encoded in cases {
+            assert_eq!(
+                decode(encoded, 4096),
+                Err(FrameError::Malformed),
+                "{:?}",
+                String::from_utf8_lossy(encoded)
+            );
+        }
+    }
+
+    #[test]
+    fn extensions_are_ignored() {
+        let body = decode(b"5;name=value\r\nhello\r\n0;last\r\n\r\n", 1024)
+            .unwrap()
+            .unwrap();
+        assert_eq!(body.data, b"hello");
+    }
+
+    #[test]
+    fn body_over_the_limit_is_rejected() {
+        assert_eq!(decode(b"400\r\n", 1024), Err(FrameError::BodyTooLarge));
+        let chunks = "1\r\na\r\n".repeat(200);
+        assert_eq!(
+            decode(chunks.as_bytes(), 1024),
+            Err(FrameError::BodyTooLarge)
+        );
+    }
+
+    #[test]
+    fn incomplete_bodies_wait_for_more() {
+        for encoded in [&b"5\r\nhel"[..], b"5\r\nhello\r\n", b"0\r\nX: y\r\n"] {
+            assert_eq!(decode(encoded, 1024), Ok(None));
+        }
+    }
+}
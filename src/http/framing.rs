@@ -0,0 +1,297 @@
+use super::{
+    chunked::{ChunkedBody, ChunkedDecoder},
+    HttpRequest, ResponseBuilder,
+};
+
+const HEADER_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+/// Where a complete request sits in the connection buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestFrame {
+    /// Length of the request line and headers, including the blank line.
+    pub head_len: usize,
+    /// Length of the whole request, head and body.
+    pub len: usize,
+    /// Decoded body and trailers when the request was sent chunked.
+    pub chunked: Option<ChunkedBody>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    HeadersTooLarge,
+    BodyTooLarge,
+    /// Both `Content-Length` and `Transfer-Encoding` were sent, or the
+    /// transfer coding is one we can't frame. Rejected to avoid smuggling.
+    AmbiguousLength,
+    Malformed,
+    /// The client stopped sending before the request was complete.
+    TimedOut,
+}
+
+impl FrameError {
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            FrameError::HeadersTooLarge => ResponseBuilder::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            FrameError::BodyTooLarge => ResponseBuilder::PAYLOAD_TOO_LARGE,
+            FrameError::AmbiguousLength | FrameError::Malformed => ResponseBuilder::BAD_REQUEST,
+            FrameError::TimedOut => ResponseBuilder::REQUEST_TIMEOUT,
+        }
+    }
+
+    pub fn response(&self) -> Vec<u8> {
+        let status = self.status();
+        ResponseBuilder::new()
+            .status(status)
+            .header("Connection", "close")
+            .text(status.1)
+            .build()
+    }
+}
+
+/// Frames requests out of a connection buffer as it fills, carrying on
+/// from where the last call stopped instead of rescanning from the start.
+#[derive(Debug, Clone, Default)]
+pub struct RequestDecoder {
+    /// How much of the buffer has been searched for the end of the head.
+    scanned: usize,
+    /// Length of the head and how its body is framed, once it's complete.
+    head: Option<(usize, BodyLength)>,
+    chunked: ChunkedDecoder,
+}
+
+impl RequestDecoder {
+    /// Looks for a complete request at the start of `buffer`, which holds
+    /// what was passed last time and possibly more. Returns `Ok(None)` when
+    /// more bytes are needed to decide. Once a request is returned the next
+    /// call starts on a new one.
+    pub fn decode(
+        &mut self,
+        buffer: &[u8],
+        max_size: usize,
+    ) -> Result<Option<RequestFrame>, FrameError> {
+        let (head_len, body_length) = match self.head {
+            Some(head) => head,
+            None => {
+                let head_len = match find_header_end(buffer, self.scanned) {
+                    Some(end) => end,
+                    None if buffer.len() > max_size => return Err(FrameError::HeadersTooLarge),
+                    None => {
+                        self.scanned = buffer.len();
+                        return Ok(None);
+                    }
+                };
+
+                if head_len > max_size {
+                    return Err(FrameError::HeadersTooLarge);
+                }
+
+                let head =
+                    std::str::from_utf8(&buffer[..head_len]).map_err(|_| FrameError::Malformed)?;
+                *self.head.insert((head_len, body_length(head)?))
+            }
+        };
+
+        let frame = match body_length {
+            BodyLength::Fixed(content_length) => {
+                // `head_len` is at most `max_size` here, and adding to the
+                // client's length could overflow.
+                if content_length > max_size - head_len {
+                    return Err(FrameError::BodyTooLarge);
+                }
+
+                let len = head_len + content_length;
+                if buffer.len() < len {
+                    return Ok(None);
+                }
+
+                RequestFrame {
+                    head_len,
+                    len,
+                    chunked: None,
+                }
+            }
+            BodyLength::Chunked => {
+                let body = match self
+                    .chunked
+                    .decode(&buffer[head_len..], max_size - head_len)?
+                {
+                    Some(body) => body,
+                    None => return Ok(None),
+                };
+
+                RequestFrame {
+                    head_len,
+                    len: head_len + body.encoded_len,
+                    chunked: Some(body),
+                }
+            }
+        };
+
+        *self = Self::default();
+        Ok(Some(frame))
+    }
+
+    /// Whether the request being read has a complete head, so the body is
+    /// what's still missing.
+    pub fn has_head(&self) -> bool {
+        self.head.is_some()
+    }
+}
+
+impl RequestFrame {
+    /// Looks for a complete request at the start of `buffer`. Returns
+    /// `Ok(None)` when more bytes are needed to decide. Connections use a
+    /// `RequestDecoder` instead, so bytes already seen aren't scanned again.
+    pub fn detect(buffer: &[u8], max_size: usize) -> Result<Option<RequestFrame>, FrameError> {
+        RequestDecoder::default().decode(buffer, max_size)
+    }
+
+    /// Parses the framed request out of `buffer` and attaches its body.
+    pub fn into_request(self, buffer: &[u8]) -> Option<HttpRequest> {
+        let mut request = HttpRequest::parse(&buffer[..self.head_len])?;
+        match self.chunked {
+            Some(chunked) => {
+                request.body = chunked.data;
+                request.trailers = chunked.trailers;
+            }
+            None => request.body = buffer[self.head_len..self.len].to_vec(),
+        }
+        Some(request)
+    }
+}
+
+/// Finds the end of the head, searching from just before `scanned` in case
+/// the terminator was split across reads.
+fn find_header_end(buffer: &[u8], scanned: usize) -> Option<usize> {
+    let from = scanned.saturating_sub(HEADER_TERMINATOR.len() - 1);
+    buffer[from..]
+        .windows(HEADER_TERMINATOR.len())
+        .position(|w| w == HEADER_TERMINATOR)
+        .map(|pos| from + pos + HEADER_TERMINATOR.len())
+}
+
+fn body_length(head: &str) -> Result<BodyLength, FrameError> {
+    let mut length = None;
+    let mut codings = Vec::new();
+
+    for line in head.lines().skip(1) {
+        if let Some((key, value)) = line.split_once(':') {
+            let key = key.trim();
+            if key.eq_ignore_ascii_case("content-length") {
+                let value = parse_content_length(value.trim())?;
+                if length.is_some_and(|existing| existing != value) {
+                    return Err(FrameError::Malformed);
+                }
+                length = Some(value);
+            } else if key.eq_ignore_ascii_case("transfer-encoding") {
+                codings.extend(value.split(',').map(|c| c.trim().to_lowercase()));
+            }
+        }
+    }
+
+    if codings.is_empty() {
+        return Ok(BodyLength::Fixed(length.unwrap_or(0)));
+    }
+
+    // Chunked has to be the only coding, and can't be combined with a
+    // Content-Length, or a proxy in front of us might frame it differently.
+    if length.is_some() || codings != ["chunked"] {
+        return Err(FrameError::AmbiguousLength);
+    }
+
+    Ok(BodyLength::Chunked)
+}
+
+/// Content-Length is nothing but digits. `usize::from_str` would also take
+/// a leading `+`, which other parsers along the way may read differently.
+fn parse_content_length(value: &str) -> Result<usize, FrameError> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(FrameError::Malformed);
+    }
+    value.parse().map_err(|_| FrameError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_split_across_reads_frames_the_same() {
+        let requests: [&[u8]; 2] = [
+            b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello",
+            b"POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        ];
+        for request in requests {
+            let whole = RequestFrame::detect(request, 1024).unwrap().unwrap();
+
+            let mut decoder = RequestDecoder::default();
+            for end in 1..request.len() {
+                assert_eq!(decoder.decode(&request[..end], 1024), Ok(None));
+            }
+            assert_eq!(decoder.decode(request, 1024), Ok(Some(whole)));
+            assert!(!decoder.has_head());
+        }
+    }
+
+    fn detect_with_length(lengths: &[&str]) -> Result<Option<RequestFrame>, FrameError> {
+        let mut request = "POST / HTTP/1.1\r\nHost: x\r\n".to_string();
+        for length in lengths {
+            request.push_str(&format!("Content-Length:{}\r\n", length));
+        }
+        request.push_str("\r\nhello");
+        RequestFrame::detect(request.as_bytes(), 1024)
+    }
+
+    #[test]
+    fn content_length_must_be_plain_digits() {
+        for length in ["", " ", "+5", "-5", "0x5", "5 5", "5,5", "5.0", "٥"] {
+            assert_eq!(
+                detect_with_length(&[length]),
+                Err(FrameError::Malformed),
+                "{:?}",
+                length
+            );
+        }
+        let frame = detect_with_length(&[" 5 "]).unwrap().unwrap();
+        assert_eq!(frame.len - frame.head_len, 5);
+    }
+
+    #[test]
+    fn repeated_content_length_has_to_agree() {
+        assert!(detect_with_length(&["5", "5"]).unwrap().is_some());
+        assert_eq!(detect_with_length(&["5", "6"]), Err(FrameError::Malformed));
+    }
+
+    #[test]
+    fn content_length_too_large_for_the_limit() {
+        let max = usize::MAX.to_string();
+        assert_eq!(detect_with_length(&[&max]), Err(FrameError::BodyTooLarge));
+        assert_eq!(
+            detect_with_length(&["99999999999999999999999999"]),
+            Err(FrameError::Malformed)
+        );
+        assert_eq!(detect_with_length(&["2000"]), Err(FrameError::BodyTooLarge));
+    }
+
+    #[test]
+    fn content_length_frames_only_its_body() {
+        assert_eq!(detect_with_length(&["6"]), Ok(None));
+
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET / HTTP/1.1\r\n\r\n";
+        let frame = RequestFrame::detect(request, 1024).unwrap().unwrap();
+        assert_eq!(&request[frame.head_len..frame.len], b"hi");
+
+        let smuggled =
+            b"POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            RequestFrame::detect(smuggled, 1024),
+            Err(FrameError::AmbiguousLength)
+        );
+    }
+}
@@ -1,13 +1,26 @@
 use std::{collections::HashMap, sync::Arc};
 
+use crate::{
+    tls::{PeerCertificate, TlsInfo},
+    websocket::{self, WebSocketUpgrade},
+};
+
 use super::{
-    files::StaticHandler, HttpMethod, HttpRequest, MiddlewareHandler, ResponseBuilder, RouteManager,
+    files::StaticHandler,
+    routes::{Route, RouteHandler},
+    sse,
+    stream::BodyStream,
+    HttpMethod, HttpRequest, MiddlewareHandler, ResponseBuilder, RouteManager, StreamResponse,
 };
 
 pub struct RequestResponse {
     pub method: HttpMethod,
     pub path: String,
     pub ip: String,
+    /// The SNI hostname of TLS connections, naming the virtual host.
+    pub server_name: Option<String>,
+    /// The protocol agreed through ALPN, such as `h2` or `http/1.1`.
+    pub alpn_protocol: Option<String>,
     pub status: u16,
     pub duration: std::time::Duration,
 }
@@ -15,11 +28,76 @@ pub struct RequestResponse {
 pub struct Res {
     pub buffer: Vec<u8>,
     pub status: u16,
+    /// Body that follows `buffer` for streamed responses, in which case
+    /// `buffer` only holds the head.
+    pub stream: Option<BodyStream>,
+    /// Set when `buffer` is a 101 response accepting a WebSocket handshake.
+    /// The connection switches over once it has been written.
+    pub websocket: Option<Box<WebSocketUpgrade>>,
 }
 
 impl Res {
     pub fn new(buffer: Vec<u8>, status: u16) -> Self {
-        Self { buffer, status }
+        Self {
+            buffer,
+            status,
+            stream: None,
+            websocket: None,
+        }
+    }
+
+    /// Sets a header on the already serialised response, replacing any
+    /// existing header with the same name.
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.rewrite_header(key, Some(value));
+    }
+
+    pub fn remove_header(&mut self, key: &str) {
+        self.rewrite_header(key, None);
+    }
+
+    /// Drops the body while keeping the head as is, including its
+    /// `Content-Length`, to answer a HEAD request.
+    pub fn strip_body(&mut self) {
+        if let Some(pos) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            self.buffer.truncate(pos + 4);
+        }
+        self.stream = None;
+        self.websocket = None;
+    }
+
+    fn rewrite_header(&mut self, key: &str, value: Option<&str>) {
+        let head_end = match self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => pos,
+            None => return,
+        };
+
+        let head = String::from_utf8_lossy(&self.buffer[..head_end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let mut new_head = lines.next().unwrap_or("").to_string();
+        for line in lines {
+            let name = line.split_once(':').map(|(k, _)| k.trim()).unwrap_or(line);
+            if !name.eq_ignore_ascii_case(key) {
+                new_head.push_str("\r\n");
+                new_head.push_str(line);
+            }
+        }
+        if let Some(value) = value {
+            new_head.push_str(&format!("\r\n{}: {}", key, value));
+        }
+
+        self.buffer.splice(..head_end, new_head.into_bytes());
+    }
+}
+
+impl From<StreamResponse> for Res {
+    fn from(response: StreamResponse) -> Self {
+        Self {
+            buffer: response.head,
+            status: response.status,
+            stream: Some(response.body),
+            websocket: None,
+        }
     }
 }
 
@@ -45,30 +123,7 @@ impl HttpHandler {
 
     pub fn handle(&self, buffer: &[u8]) -> Res {
         match HttpRequest::parse(buffer) {
-            Some(request) => {
-                if let Some(file_path) = self.static_files.get(&request.path) {
-                    if let Some((data, mime)) = StaticHandler::serve(file_path) {
-                        return Res::new(
-                            ResponseBuilder::ok()
-                                .header("Content-Type", mime.as_str())
-                                .body(data)
-                                .build(),
-                            200,
-                        );
-                    }
-                }
-
-                if let Some(route) = self.routes.find_route(&request.path, request.method) {
-                    let params = self.extract_params(&route.pattern, &request.path);
-                    let context = Context { request, params };
-                    match self.middleware.run(context, route) {
-                        Ok(ctx) => Res::new((route.handler)(&ctx), 200),
-                        Err(res) => res,
-                    }
-                } else {
-                    Res::new(ResponseBuilder::not_found().text("Not Found").build(), 404)
-                }
-            }
+            Some(request) => self.handle_request(request, None),
             None => Res::new(
                 ResponseBuilder::bad_request().text("Bad Request").build(),
                 400,
@@ -76,6 +131,124 @@ impl HttpHandler {
         }
     }
 
+    /// Answers `request`. `tls` describes the connection it arrived on when
+    /// that is encrypted.
+    ///
+    /// HEAD requests are answered by the GET route without the body, and
+    /// OPTIONS requests without a route of their own list the allowed
+    /// methods. Paths that exist for other methods only get a 405.
+    ///
+    /// For HEAD, streaming routes still run their handler for the head but
+    /// the body is dropped unread, SSE handlers aren't started at all and
+    /// WebSocket routes get a 405.
+    pub fn handle_request(&self, request: HttpRequest, tls: Option<Arc<TlsInfo>>) -> Res {
+        let head = request.method == HttpMethod::Head;
+        let mut response = self.dispatch(request, tls);
+        if head {
+            response.strip_body();
+        }
+        response
+    }
+
+    fn dispatch(&self, request: HttpRequest, tls: Option<Arc<TlsInfo>>) -> Res {
+        let head = request.method == HttpMethod::Head;
+        let method = match request.method {
+            HttpMethod::Head => HttpMethod::Get,
+            method => method,
+        };
+        // A WebSocket handshake has no HEAD equivalent.
+        let route = self
+            .routes
+            .find_route(&request.path, method)
+            .filter(|route| !(head && is_websocket(route)));
+
+        if method == HttpMethod::Options && route.is_none() {
+            return match self.allowed_methods(&request.path) {
+                Some(allow) => Res::new(
+                    ResponseBuilder::new()
+                        .status(ResponseBuilder::NO_CONTENT)
+                        .header("Allow", &allow)
+                        .build(),
+                    204,
+                ),
+                None => Res::new(ResponseBuilder::not_found().text("Not Found").build(), 404),
+            };
+        }
+
+        let static_file = match method {
+            HttpMethod::Get => self.static_files.get(&request.path),
+            _ => None,
+        };
+        if let Some(file_path) = static_file {
+            if let Some((data, mime)) = StaticHandler::serve(file_path) {
+                return Res::new(
+                    ResponseBuilder::ok()
+                        .header("Content-Type", mime.as_str())
+                        .body(data)
+                        .build(),
+                    200,
+                );
+            }
+        }
+
+        if let Some(route) = route {
+            let params = self.extract_params(&route.pattern, &request.path);
+            let context = Context {
+                request,
+                params,
+                tls,
+            };
+            match self.middleware.run(context, route) {
+                Ok(ctx) => match route.handler {
+                    RouteHandler::Buffered(handler) => Res::new(handler(&ctx), 200),
+                    RouteHandler::Stream(handler) => handler(&ctx).into(),
+                    RouteHandler::Sse(_) if head => sse::head(),
+                    RouteHandler::Sse(handler) => sse::start(ctx, handler),
+                    RouteHandler::WebSocket(handler) => websocket::accept(ctx, handler),
+                },
+                Err(res) => res,
+            }
+        } else if let Some(allow) = self.allowed_methods(&request.path) {
+            // The path exists, just not for this method.
+            Res::new(
+                ResponseBuilder::method_not_allowed()
+                    .header("Allow", &allow)
+                    .text("Method Not Allowed")
+                    .build(),
+                405,
+            )
+        } else {
+            Res::new(ResponseBuilder::not_found().text("Not Found").build(), 404)
+        }
+    }
+
+    /// The `Allow` header value for `path`, or `None` if nothing is served
+    /// there. GET implies HEAD unless it's a WebSocket route, and OPTIONS
+    /// is always answered.
+    fn allowed_methods(&self, path: &str) -> Option<String> {
+        let mut methods = self.routes.methods_for(path);
+        let static_file = self.static_files.contains_key(path);
+        if static_file && !methods.contains(&HttpMethod::Get) {
+            methods.insert(0, HttpMethod::Get);
+        }
+        if methods.is_empty() {
+            return None;
+        }
+        let head = static_file
+            || self
+                .routes
+                .find_route(path, HttpMethod::Get)
+                .is_some_and(|route| !is_websocket(route));
+        if head && !methods.contains(&HttpMethod::Head) {
+            methods.push(HttpMethod::Head);
+        }
+        if !methods.contains(&HttpMethod::Options) {
+            methods.push(HttpMethod::Options);
+        }
+        let methods: Vec<String> = methods.iter().map(ToString::to_string).collect();
+        Some(methods.join(", "))
+    }
+
     fn extract_params(&self, pattern: &str, path: &str) -> HashMap<String, String> {
         let mut params = HashMap::new();
         let pattern_parts: Vec<_> = pattern.split('/').collect();
@@ -90,13 +263,63 @@ impl HttpHandler {
     }
 }
 
+fn is_websocket(route: &Route) -> bool {
+    matches!(route.handler, RouteHandler::WebSocket(_))
+}
+
 pub struct Context {
     pub request: HttpRequest,
     params: HashMap<String, String>,
+    tls: Option<Arc<TlsInfo>>,
 }
 
 impl Context {
     pub fn param(&self, key: &str) -> Option<&str> {
         self.params.get(key).map(|s| s.as_str())
     }
+
+    /// The TLS session the request arrived on, or `None` over plain HTTP.
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_deref()
+    }
+
+    /// The configured hostname whose certificate answered, naming the
+    /// virtual host that served the request. A wildcard match gives the
+    /// pattern, such as `*.example.com`, and the default certificate gives
+    /// `None`. The raw SNI is in `tls().sni`.
+    pub fn server_name(&self) -> Option<&str> {
+        self.tls()?.server_name.as_deref()
+    }
+
+    /// The client certificate verified during the handshake, for
+    /// middleware authorising mutual TLS clients.
+    pub fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.tls()?.peer_certificate.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> HttpHandler {
+        let mut static_files = HashMap::new();
+        static_files.insert("/index.html".to_string(), "index.html");
+        HttpHandler::new(
+            Arc::new(RouteManager::new()),
+            Arc::new(MiddlewareHandler::new()),
+            Arc::new(static_files),
+        )
+    }
+
+    #[test]
+    fn static_files_only_answer_get_and_head() {
+        let response = handler().handle(b"GET /index.html HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(response.status, 200);
+
+        let response = handler().handle(b"DELETE /index.html HTTP/1.1\r\nHost: x\r\n\r\n");
+        let head = String::from_utf8_lossy(&response.buffer);
+        assert_eq!(response.status, 405);
+        assert!(head.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+    }
 }
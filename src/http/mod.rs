@@ -1,14 +1,22 @@
+mod chunked;
 mod files;
+mod framing;
 mod handler;
 mod middleware;
 mod mime;
 mod request;
 mod response;
 mod routes;
+mod sse;
+mod stream;
 
+pub use chunked::{encode_chunk, ChunkedBody, LAST_CHUNK};
 pub use files::StaticHandler;
-pub use handler::{Context, HttpHandler, RequestResponse};
+pub use framing::{FrameError, RequestDecoder, RequestFrame};
+pub use handler::{Context, HttpHandler, RequestResponse, Res};
 pub use middleware::{MiddlewareFn, MiddlewareHandler, MiddlewareResult};
-pub use request::{HttpMethod, HttpRequest};
+pub use request::{HttpMethod, HttpRequest, HttpVersion};
 pub use response::ResponseBuilder;
-pub use routes::RouteManager;
+pub use routes::{RouteHandler, RouteManager};
+pub use sse::{Event, EventSender, EventStream, SseFuture, SseHandler};
+pub use stream::{BodyStream, StreamResponse};
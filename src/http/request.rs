@@ -7,6 +7,10 @@ pub enum HttpMethod {
     Put,
     Patch,
     Delete,
+    Head,
+    Options,
+    Connect,
+    Trace,
     Unknown,
 }
 
@@ -20,17 +24,42 @@ impl FromStr for HttpMethod {
             "PUT" => Ok(HttpMethod::Put),
             "PATCH" => Ok(HttpMethod::Patch),
             "DELETE" => Ok(HttpMethod::Delete),
+            "HEAD" => Ok(HttpMethod::Head),
+            "OPTIONS" => Ok(HttpMethod::Options),
+            "CONNECT" => Ok(HttpMethod::Connect),
+            "TRACE" => Ok(HttpMethod::Trace),
             _ => Ok(HttpMethod::Unknown),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "HTTP/1.0" => Ok(HttpVersion::Http10),
+            "HTTP/1.1" => Ok(HttpVersion::Http11),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
+    pub version: HttpVersion,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    pub trailers: HashMap<String, String>,
     pub query_params: Params,
     pub path_params: Params,
     pub cookies: Cookies,
@@ -52,8 +81,10 @@ impl HttpRequest {
         Self {
             method,
             path,
+            version: HttpVersion::Http11,
             headers,
             body,
+            trailers: HashMap::new(),
             query_params,
             path_params,
             cookies,
@@ -81,12 +112,36 @@ impl HttpRequest {
         serde_json::from_slice(&self.body).ok()
     }
 
+    /// Whether the client wants the connection kept open after this request.
+    /// An explicit `Connection` header wins, otherwise HTTP/1.1 defaults to
+    /// persistent connections and HTTP/1.0 does not.
+    pub fn keep_alive(&self) -> bool {
+        if self.has_token("connection", "close") {
+            return false;
+        }
+        if self.has_token("connection", "keep-alive") {
+            return true;
+        }
+        self.version == HttpVersion::Http11
+    }
+
+    /// Whether the comma separated header `name` lists `token`, ignoring
+    /// case, as with `Connection: keep-alive, Upgrade`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.headers.get(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    }
+
     /** Static interface */
     pub fn parse(buffer: &[u8]) -> Option<HttpRequest> {
-        let request = std::str::from_utf8(buffer).ok()?;
-        let mut parts = request.split("\r\n\r\n");
-        let headers_part = parts.next()?;
-        let body_part = parts.next().unwrap_or("");
+        let (headers_part, body_part) = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => (&buffer[..pos], &buffer[pos + 4..]),
+            None => (buffer, &[][..]),
+        };
+        let headers_part = std::str::from_utf8(headers_part).ok()?;
 
         let mut lines = headers_part.lines();
         let request_line = lines.next()?;
@@ -94,6 +149,10 @@ impl HttpRequest {
 
         let method = HttpMethod::from_str(parts.next()?).ok()?;
         let path = parts.next()?.to_string();
+        let version = match parts.next() {
+            Some(version) => HttpVersion::from_str(version).ok()?,
+            None => HttpVersion::Http11,
+        };
 
         let mut headers = HashMap::new();
         for line in lines {
@@ -102,25 +161,43 @@ impl HttpRequest {
             }
         }
 
+        Some(HttpRequest::from_parts(
+            method,
+            path,
+            version,
+            headers,
+            body_part.to_vec(),
+        ))
+    }
+
+    /// Builds a request from already parsed parts, deriving cookies and
+    /// query parameters from them.
+    pub fn from_parts(
+        method: HttpMethod,
+        path: String,
+        version: HttpVersion,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    ) -> HttpRequest {
         let cookies = match headers.get("cookie") {
             Some(cookie_str) => HttpRequest::parse_cookies(cookie_str.as_str()),
             None => HashMap::new(),
         };
 
-        let body = body_part.as_bytes().to_vec();
-
         let query_params = HttpRequest::parse_query_params(&path.as_str());
         let path_params = HttpRequest::parse_path_params(&path.as_str(), &path);
 
-        Some(HttpRequest {
+        HttpRequest {
             method,
             path,
+            version,
             headers,
             body,
+            trailers: HashMap::new(),
             query_params,
             path_params,
             cookies,
-        })
+        }
     }
 
     /** Private interface */
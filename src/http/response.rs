@@ -2,6 +2,8 @@ use flate2::{write::GzEncoder, Compression};
 use std::io::Write;
 use crate::Logger;
 
+use super::stream::{BodyStream, StreamResponse};
+
 #[derive(Default)]
 pub struct ResponseBuilder {
     status_line: String,
@@ -11,14 +13,24 @@ pub struct ResponseBuilder {
 
 impl ResponseBuilder {
     // Status code constants
+    pub const SWITCHING_PROTOCOLS: (u16, &'static str) = (101, "Switching Protocols");
     pub const OK: (u16, &'static str) = (200, "OK");
     pub const CREATED: (u16, &'static str) = (201, "Created");
     pub const UPDATED: (u16, &'static str) = (200, "Success");
     pub const NO_CONTENT: (u16, &'static str) = (204, "No Content");
     pub const DELETED: (u16, &'static str) = (200, "Success");
+    pub const MOVED_PERMANENTLY: (u16, &'static str) = (301, "Moved Permanently");
+    pub const PERMANENT_REDIRECT: (u16, &'static str) = (308, "Permanent Redirect");
     pub const NOT_FOUND: (u16, &'static str) = (404, "Not Found");
     pub const BAD_REQUEST: (u16, &'static str) = (400, "Bad Request");
+    pub const METHOD_NOT_ALLOWED: (u16, &'static str) = (405, "Method Not Allowed");
+    pub const REQUEST_TIMEOUT: (u16, &'static str) = (408, "Request Timeout");
+    pub const PAYLOAD_TOO_LARGE: (u16, &'static str) = (413, "Payload Too Large");
+    pub const UPGRADE_REQUIRED: (u16, &'static str) = (426, "Upgrade Required");
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: (u16, &'static str) =
+        (431, "Request Header Fields Too Large");
     pub const INTERNAL_SERVER_ERROR: (u16, &'static str) = (500, "Internal Server Error");
+    pub const SERVICE_UNAVAILABLE: (u16, &'static str) = (503, "Service Unavailable");
 
     // Content type constants
     pub const PLAIN: &'static str = "text/plain";
@@ -73,14 +85,17 @@ impl ResponseBuilder {
             .map(|(_, v)| v.as_str())
     }
 
-    fn should_compress(&self) -> bool {
+    fn is_compressible(&self) -> bool {
         self.headers.iter()
             .any(|(k, v)| k == "Content-Type" && Self::COMPRESSIBLE_TYPES.contains(&v.as_str()))
-            && self.body.len() > Self::MIN_COMPRESS_SIZE
             && self.get_accepted_encoding()
             .map_or(false, |enc| enc.to_lowercase().contains("gzip"))
     }
 
+    fn should_compress(&self) -> bool {
+        self.is_compressible() && self.body.len() > Self::MIN_COMPRESS_SIZE
+    }
+
     fn compress_body(&mut self) -> bool {
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
         match encoder.write_all(&self.body)
@@ -96,33 +111,69 @@ impl ResponseBuilder {
         }
     }
 
-    pub fn build(mut self) -> Vec<u8> {
-
-        if self.should_compress() {
-            let logger = Logger::new();
-            logger.log(
-                crate::logger::LogLevel::Info,
-                "Compressing response body",
-            );
-            self.compress_body();
-        }
-
-        let mut response = Vec::new();
+    fn status_code(&self) -> u16 {
+        self.status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(200)
+    }
 
+    fn write_head(&self, response: &mut Vec<u8>) {
         // Add status line
         response.extend_from_slice(self.status_line.as_bytes());
         response.extend_from_slice(b"\r\n");
 
         // Add headers
-        for (key, value) in self.headers {
+        for (key, value) in &self.headers {
             response.extend_from_slice(key.as_bytes());
             response.extend_from_slice(b": ");
             response.extend_from_slice(value.as_bytes());
             response.extend_from_slice(b"\r\n");
         }
 
-        // Add blank line and body
         response.extend_from_slice(b"\r\n");
+    }
+
+    /// Finishes the response with a body that is sent chunk by chunk using
+    /// `Transfer-Encoding: chunked`. Compression is applied per chunk, since
+    /// the final size isn't known up front.
+    pub fn stream(mut self, body: BodyStream) -> StreamResponse {
+        self.headers.retain(|(k, _)| k != "Content-Length");
+
+        let body = if self.is_compressible() {
+            self.headers.push(("Content-Encoding".to_string(), "gzip".to_string()));
+            body.gzip()
+        } else {
+            body
+        };
+        self.headers.push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+
+        let mut head = Vec::new();
+        self.write_head(&mut head);
+
+        StreamResponse {
+            status: self.status_code(),
+            head,
+            body,
+        }
+    }
+
+    pub fn build(mut self) -> Vec<u8> {
+
+        if self.should_compress() {
+            let logger = Logger::new();
+            logger.log(
+                crate::logger::LogLevel::Info,
+                "Compressing response body",
+            );
+            self.compress_body();
+        }
+
+        let mut response = Vec::new();
+
+        // Add status line, headers and blank line, then the body
+        self.write_head(&mut response);
         response.extend_from_slice(&self.body);
 
         response
@@ -196,6 +247,10 @@ impl ResponseBuilder {
         Self::new().status(Self::BAD_REQUEST)
     }
 
+    pub fn method_not_allowed() -> Self {
+        Self::new().status(Self::METHOD_NOT_ALLOWED)
+    }
+
     pub fn server_error() -> Self {
         Self::new().status(Self::INTERNAL_SERVER_ERROR)
     }
@@ -1,6 +1,6 @@
-use crate::{logger, Logger};
+use crate::{logger, websocket::WebSocketHandler, Logger};
 
-use super::{handler::Context, HttpMethod};
+use super::{handler::Context, sse::SseHandler, stream::StreamResponse, HttpMethod};
 
 #[derive(Debug, Clone, Default)]
 pub struct RouteManager {
@@ -39,22 +39,70 @@ impl RouteManager {
     }
 
     pub fn get(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
-        self.add_route(Route::new(path, HttpMethod::Get, handler));
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Get,
+            RouteHandler::Buffered(handler),
+        ));
         self
     }
 
     pub fn post(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
-        self.add_route(Route::new(path, HttpMethod::Post, handler));
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Post,
+            RouteHandler::Buffered(handler),
+        ));
         self
     }
 
     pub fn put(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
-        self.add_route(Route::new(path, HttpMethod::Put, handler));
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Put,
+            RouteHandler::Buffered(handler),
+        ));
         self
     }
 
     pub fn delete(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
-        self.add_route(Route::new(path, HttpMethod::Delete, handler));
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Delete,
+            RouteHandler::Buffered(handler),
+        ));
+        self
+    }
+
+    /// Registers a GET route whose response body is streamed to the client.
+    pub fn get_stream(&mut self, path: &str, handler: fn(&Context) -> StreamResponse) -> &mut Self {
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Get,
+            RouteHandler::Stream(handler),
+        ));
+        self
+    }
+
+    /// Registers a GET route that streams Server-Sent Events. `handler` runs
+    /// for as long as the client stays connected.
+    pub fn sse(&mut self, path: &str, handler: SseHandler) -> &mut Self {
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Get,
+            RouteHandler::Sse(handler),
+        ));
+        self
+    }
+
+    /// Registers a GET route that accepts WebSocket connections. `handler`
+    /// runs once the handshake succeeds, for as long as the connection lives.
+    pub fn websocket(&mut self, path: &str, handler: WebSocketHandler) -> &mut Self {
+        self.add_route(Route::new(
+            path,
+            HttpMethod::Get,
+            RouteHandler::WebSocket(handler),
+        ));
         self
     }
 
@@ -86,19 +134,53 @@ impl RouteManager {
             .iter()
             .find(|r| r.method == method && r.matches(path))
     }
+
+    /// The methods with a route registered for `path`, in registration
+    /// order. `*` asks for every method registered on any path.
+    pub fn methods_for(&self, path: &str) -> Vec<HttpMethod> {
+        let mut methods = Vec::new();
+        for route in &self.routes {
+            if (path == "*" || route.matches(path)) && !methods.contains(&route.method) {
+                methods.push(route.method);
+            }
+        }
+        methods
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RouteHandler {
+    Buffered(fn(&Context) -> Vec<u8>),
+    Stream(fn(&Context) -> StreamResponse),
+    Sse(SseHandler),
+    WebSocket(WebSocketHandler),
+}
+
+impl PartialEq for RouteHandler {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Buffered(a), Self::Buffered(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Self::Stream(a), Self::Stream(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Self::Sse(a), Self::Sse(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Self::WebSocket(a), Self::WebSocket(b)) => std::ptr::fn_addr_eq(*a, *b),
+            _ => false,
+        }
+    }
 }
 
+impl Eq for RouteHandler {}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Route {
     pub pattern: String,
     pub raw_path: String,
     pub path_params: Vec<String>,
     pub method: HttpMethod,
-    pub handler: fn(&Context) -> Vec<u8>,
+    pub handler: RouteHandler,
 }
 
 impl Route {
-    pub fn new(pattern: &str, method: HttpMethod, handler: fn(&Context) -> Vec<u8>) -> Self {
+    pub fn new(pattern: &str, method: HttpMethod, handler: RouteHandler) -> Self {
         let path_params = pattern
             .split('/')
             .filter(|s| s.starts_with(':'))
@@ -156,29 +238,71 @@ impl RouteGroup {
 
     pub fn get(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
         let full_path = format!("{}{}", self.prefix, path);
-        self.routes
-            .push(Route::new(&full_path, HttpMethod::Get, handler));
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Get,
+            RouteHandler::Buffered(handler),
+        ));
         self
     }
 
     pub fn post(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
         let full_path = format!("{}{}", self.prefix, path);
-        self.routes
-            .push(Route::new(&full_path, HttpMethod::Post, handler));
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Post,
+            RouteHandler::Buffered(handler),
+        ));
         self
     }
 
     pub fn put(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
         let full_path = format!("{}{}", self.prefix, path);
-        self.routes
-            .push(Route::new(&full_path, HttpMethod::Put, handler));
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Put,
+            RouteHandler::Buffered(handler),
+        ));
         self
     }
 
     pub fn delete(&mut self, path: &str, handler: fn(&Context) -> Vec<u8>) -> &mut Self {
         let full_path = format!("{}{}", self.prefix, path);
-        self.routes
-            .push(Route::new(&full_path, HttpMethod::Delete, handler));
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Delete,
+            RouteHandler::Buffered(handler),
+        ));
+        self
+    }
+
+    pub fn get_stream(&mut self, path: &str, handler: fn(&Context) -> StreamResponse) -> &mut Self {
+        let full_path = format!("{}{}", self.prefix, path);
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Get,
+            RouteHandler::Stream(handler),
+        ));
+        self
+    }
+
+    pub fn sse(&mut self, path: &str, handler: SseHandler) -> &mut Self {
+        let full_path = format!("{}{}", self.prefix, path);
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Get,
+            RouteHandler::Sse(handler),
+        ));
+        self
+    }
+
+    pub fn websocket(&mut self, path: &str, handler: WebSocketHandler) -> &mut Self {
+        let full_path = format!("{}{}", self.prefix, path);
+        self.routes.push(Route::new(
+            &full_path,
+            HttpMethod::Get,
+            RouteHandler::WebSocket(handler),
+        ));
         self
     }
 
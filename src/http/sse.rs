@@ -0,0 +1,167 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use super::{handler::Context, stream::BodyStream, Res, ResponseBuilder};
+
+/// How long the stream may sit idle before a comment is sent to keep
+/// proxies from timing it out.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const KEEP_ALIVE_COMMENT: &[u8] = b": keep-alive\n\n";
+
+/// Events that can be queued before `send` waits for the client.
+const EVENT_CAPACITY: usize = 16;
+
+pub type SseFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Produces the events for one client. The stream ends once the returned
+/// future completes.
+pub type SseHandler = fn(EventStream) -> SseFuture;
+
+/// A single `text/event-stream` event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the id the browser sends back in `Last-Event-ID` when it
+    /// reconnects.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Sets the event type, which picks the listener on the client.
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Sets the payload. Multi-line data is split across `data:` lines.
+    pub fn data(mut self, data: impl Into<String>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// Sets how long the client waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            out.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            // A NUL in the id makes clients ignore the field.
+            out.push_str(&format!("id: {}\n", single_line(id).replace('\0', "")));
+        }
+        if let Some(retry) = self.retry {
+            out.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            for line in data.split("\r\n").flat_map(|l| l.split(['\r', '\n'])) {
+                out.push_str(&format!("data: {}\n", line));
+            }
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
+}
+
+/// Newlines would end the field early, so they're dropped.
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// Sends events to the client. It can be cloned and moved to other tasks.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl EventSender {
+    /// Queues `event`, waiting if the client is falling behind. Fails once
+    /// the client has gone away.
+    pub async fn send(&self, event: Event) -> io::Result<()> {
+        self.tx
+            .send(event.encode())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event stream closed"))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// An open event stream, as handed to an `SseHandler`.
+pub struct EventStream {
+    context: Context,
+    sender: EventSender,
+}
+
+impl EventStream {
+    /// The request that opened the stream, with its route parameters.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// The id of the last event a reconnecting client received, so the
+    /// handler can resume after it.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.context
+            .request
+            .headers
+            .get("last-event-id")
+            .map(|id| id.as_str())
+    }
+
+    pub async fn send(&self, event: Event) -> io::Result<()> {
+        self.sender.send(event).await
+    }
+
+    pub fn sender(&self) -> EventSender {
+        self.sender.clone()
+    }
+}
+
+/// Starts `handler` and returns the streamed response it feeds.
+pub(crate) fn start(context: Context, handler: SseHandler) -> Res {
+    let (tx, body) = BodyStream::channel(EVENT_CAPACITY);
+    let body = body.keep_alive(KEEP_ALIVE_INTERVAL, KEEP_ALIVE_COMMENT.to_vec());
+
+    tokio::spawn(handler(EventStream {
+        context,
+        sender: EventSender { tx },
+    }));
+
+    respond(body)
+}
+
+/// The head `start` responds with, answering HEAD without starting a
+/// handler.
+pub(crate) fn head() -> Res {
+    respond(BodyStream::from_chunks(Vec::new()))
+}
+
+fn respond(body: BodyStream) -> Res {
+    ResponseBuilder::ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .stream(body)
+        .into()
+}
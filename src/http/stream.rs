@@ -0,0 +1,110 @@
+use flate2::{write::GzEncoder, Compression};
+use std::io::Write;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// A response body produced a chunk at a time, so large payloads never have
+/// to be held in memory at once.
+pub struct BodyStream {
+    source: Source,
+    gzip: Option<Box<GzEncoder<Vec<u8>>>>,
+    /// Chunk written whenever a live source stays quiet this long.
+    keep_alive: Option<(Duration, Vec<u8>)>,
+}
+
+enum Source {
+    Iter(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+    Channel(mpsc::Receiver<Vec<u8>>),
+}
+
+impl BodyStream {
+    /// Streams the chunks yielded by `iter`.
+    pub fn from_chunks<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        Self {
+            source: Source::Iter(Box::new(iter.into_iter())),
+            gzip: None,
+            keep_alive: None,
+        }
+    }
+
+    /// Creates a stream fed by the returned sender. The body ends when every
+    /// sender has been dropped.
+    pub fn channel(capacity: usize) -> (mpsc::Sender<Vec<u8>>, Self) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let stream = Self {
+            source: Source::Channel(rx),
+            gzip: None,
+            keep_alive: None,
+        };
+        (tx, stream)
+    }
+
+    pub(crate) fn gzip(mut self) -> Self {
+        self.gzip = Some(Box::new(GzEncoder::new(Vec::new(), Compression::default())));
+        self
+    }
+
+    /// Writes `chunk` whenever the channel has been idle for `interval`, so
+    /// intermediaries don't drop a quiet connection.
+    pub(crate) fn keep_alive(mut self, interval: Duration, chunk: Vec<u8>) -> Self {
+        self.keep_alive = Some((interval, chunk));
+        self
+    }
+
+    /// Whether chunks arrive at the producer's pace rather than on demand,
+    /// meaning each one should be flushed as soon as it's written.
+    pub(crate) fn is_live(&self) -> bool {
+        matches!(self.source, Source::Channel(_))
+    }
+
+    /// Returns the next chunk to write, compressed if gzip was negotiated.
+    /// Returns `None` once the body is finished.
+    pub async fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        let chunk = match &mut self.source {
+            Source::Iter(iter) => iter.next(),
+            Source::Channel(rx) => match &self.keep_alive {
+                Some((interval, chunk)) => tokio::time::timeout(*interval, rx.recv())
+                    .await
+                    .unwrap_or_else(|_| Some(chunk.clone())),
+                None => rx.recv().await,
+            },
+        };
+
+        match (chunk, self.gzip.as_mut()) {
+            (Some(chunk), None) => Some(chunk),
+            (Some(chunk), Some(encoder)) => {
+                let _ = encoder.write_all(&chunk);
+                // Live chunks are sync flushed so the client can decode what
+                // it has received so far. Otherwise the encoder emits output
+                // as its window fills, which may be nothing for this chunk.
+                if matches!(self.source, Source::Channel(_)) {
+                    let _ = encoder.flush();
+                }
+                Some(std::mem::take(encoder.get_mut()))
+            }
+            (None, None) => None,
+            (None, Some(_)) => self.gzip.take().and_then(|encoder| encoder.finish().ok()),
+        }
+    }
+}
+
+impl std::fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("gzip", &self.gzip.is_some())
+            .finish()
+    }
+}
+
+/// A response whose head is written up front and whose body follows as a
+/// stream of chunks.
+#[derive(Debug)]
+pub struct StreamResponse {
+    pub status: u16,
+    pub head: Vec<u8>,
+    pub body: BodyStream,
+}
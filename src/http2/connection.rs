@@ -0,0 +1,1205 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, Semaphore},
+    time,
+};
+
+use super::{
+    frame::{
+        flags, parse_settings, ErrorCode, Frame, FrameType, SettingId, DEFAULT_MAX_FRAME_SIZE,
+        DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE,
+    },
+    hpack::{Decoder, Encoder, HeaderList, HpackError},
+};
+use crate::{
+    config::Config,
+    connection::within,
+    http::{FrameError, HttpHandler, HttpMethod, HttpRequest, HttpVersion, RequestResponse, Res},
+    logger::{LogLevel, Logger},
+    shutdown::Shutdown,
+    tls::TlsInfo,
+};
+
+/// Client connection preface that opens every HTTP/2 connection.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+/// Upper bound on a compressed header block across its CONTINUATION frames.
+const MAX_HEADER_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Headers that only make sense for a single HTTP/1.1 hop and are not
+/// allowed in HTTP/2 messages.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+enum H2Error {
+    Connection(ErrorCode),
+    Stream(u32, ErrorCode),
+}
+
+impl From<ErrorCode> for H2Error {
+    fn from(code: ErrorCode) -> Self {
+        H2Error::Connection(code)
+    }
+}
+
+type FrameResult = Result<(), H2Error>;
+
+/// A chunk from a streamed response body, or `None` once it has ended.
+type BodyChunk = (u32, Option<Vec<u8>>);
+
+struct Stream {
+    header_block: Vec<u8>,
+    headers: HeaderList,
+    body: Vec<u8>,
+    /// END_STREAM arrived on a HEADERS frame still waiting on CONTINUATION.
+    ends_with_headers: bool,
+    /// The client has finished sending (half-closed remote).
+    remote_closed: bool,
+    /// A response was already queued, so further body data is dropped.
+    responded: bool,
+    send_window: i64,
+    pending: Vec<u8>,
+    sent: usize,
+    /// Set while a streamed body is still producing chunks. A permit is
+    /// added each time we're ready for the next one.
+    body_permits: Option<Arc<Semaphore>>,
+    awaiting_chunk: bool,
+    /// Every byte of the response has been queued.
+    finished: bool,
+    end_sent: bool,
+    /// When the client has to have finished sending the headers, or the
+    /// body once they're in. `None` once the request is complete.
+    read_deadline: Option<time::Instant>,
+    /// DATA bytes, padding included, holding the connection window until
+    /// the body is handed off.
+    unreleased: usize,
+}
+
+impl Stream {
+    fn new(send_window: i64) -> Self {
+        Self {
+            header_block: Vec::new(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            ends_with_headers: false,
+            remote_closed: false,
+            responded: false,
+            send_window,
+            pending: Vec::new(),
+            sent: 0,
+            body_permits: None,
+            awaiting_chunk: false,
+            finished: false,
+            end_sent: false,
+            unreleased: 0,
+            read_deadline: None,
+        }
+    }
+
+    fn unsent(&self) -> usize {
+        self.pending.len() - self.sent
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // Stops the task pumping a streamed body for a reset stream.
+        if let Some(permits) = &self.body_permits {
+            permits.close();
+        }
+    }
+}
+
+/// Drives a single HTTP/2 connection: frame decoding, HPACK, stream
+/// multiplexing and flow control. Requests are dispatched to the same
+/// `HttpHandler` used for HTTP/1.1.
+pub struct Http2Connection<S> {
+    stream: S,
+    buffer: BytesMut,
+    http_handler: Arc<HttpHandler>,
+    config: Arc<Config>,
+    ip: String,
+    tls: Option<Arc<TlsInfo>>,
+    logger: Logger,
+
+    decoder: Decoder,
+    encoder: Encoder,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    /// Stream whose header block still expects CONTINUATION frames.
+    continuation: Option<u32>,
+
+    send_window: i64,
+    recv_window: i64,
+    peer_initial_window: i64,
+    peer_max_frame_size: usize,
+    going_away: bool,
+    shutdown: Shutdown,
+    /// When the connection is closed if no stream is open by then. Only
+    /// moved on when a request completes.
+    idle_deadline: time::Instant,
+
+    out: Vec<u8>,
+    chunks_tx: mpsc::UnboundedSender<BodyChunk>,
+    chunks_rx: mpsc::UnboundedReceiver<BodyChunk>,
+}
+
+impl<S> Http2Connection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// `buffer` may already hold bytes read while detecting the protocol.
+    pub fn new(
+        stream: S,
+        buffer: BytesMut,
+        http_handler: Arc<HttpHandler>,
+        config: Arc<Config>,
+        ip: String,
+        tls: Option<Arc<TlsInfo>>,
+    ) -> Self {
+        let (chunks_tx, chunks_rx) = mpsc::unbounded_channel();
+
+        Self {
+            stream,
+            buffer,
+            http_handler,
+            config,
+            ip,
+            tls,
+            logger: Logger::new(),
+            decoder: Decoder::default(),
+            encoder: Encoder::new(),
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+            continuation: None,
+            send_window: DEFAULT_WINDOW_SIZE,
+            recv_window: DEFAULT_WINDOW_SIZE,
+            peer_initial_window: DEFAULT_WINDOW_SIZE,
+            peer_max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            going_away: false,
+            shutdown: Shutdown::new(),
+            idle_deadline: time::Instant::now(),
+            out: Vec::new(),
+            chunks_tx,
+            chunks_rx,
+        }
+    }
+
+    /// Sends GOAWAY once `shutdown` is triggered, closing the connection
+    /// when the streams already open have been answered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn serve(mut self) -> io::Result<()> {
+        self.queue_settings();
+        self.start().await
+    }
+
+    /// Turns the connection away with a GOAWAY carrying `code` instead of
+    /// serving it.
+    pub async fn refuse(mut self, code: ErrorCode) -> io::Result<()> {
+        self.queue_settings();
+        self.close(code).await
+    }
+
+    /// Continues a connection that switched over from HTTP/1.1 with
+    /// `Upgrade: h2c`. `settings` is the decoded `HTTP2-Settings` payload and
+    /// `request` is answered as stream 1.
+    pub async fn serve_upgrade(mut self, request: HttpRequest, settings: &[u8]) -> io::Result<()> {
+        self.queue_settings();
+        // The 101 response acknowledges these, so no SETTINGS ACK is sent.
+        if self.apply_settings(settings).is_err() {
+            return self.close(ErrorCode::ProtocolError).await;
+        }
+
+        // Stream 1 is half-closed (remote) from the start, since the
+        // request was sent in full over HTTP/1.1.
+        let mut stream = Stream::new(self.peer_initial_window);
+        stream.remote_closed = true;
+        self.streams.insert(1, stream);
+        self.last_stream_id = 1;
+        if let Err(H2Error::Connection(code) | H2Error::Stream(_, code)) = self.answer(1, request) {
+            return self.close(code).await;
+        }
+
+        self.start().await
+    }
+
+    fn queue_settings(&mut self) {
+        self.queue(Frame::settings(&[
+            (SettingId::MaxConcurrentStreams, MAX_CONCURRENT_STREAMS),
+            (
+                SettingId::MaxHeaderListSize,
+                self.config.max_request_size as u32,
+            ),
+        ]));
+
+        // Bodies are buffered until the request is complete, so the
+        // connection window has to fit the largest one, and a byte more to
+        // tell that one is too large.
+        let window =
+            (self.config.max_request_size as i64 + 1).clamp(DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE);
+        if window > self.recv_window {
+            self.queue(Frame::window_update(0, (window - self.recv_window) as u32));
+            self.recv_window = window;
+        }
+    }
+
+    /// Sends what's been queued, then waits for the client preface before
+    /// handling frames. The preface has to arrive within
+    /// `header_read_timeout`.
+    async fn start(&mut self) -> io::Result<()> {
+        self.flush().await?;
+
+        let deadline = time::Instant::now() + self.config.header_read_timeout;
+        while self.buffer.len() < PREFACE.len() {
+            match time::timeout_at(deadline, self.stream.read_buf(&mut self.buffer)).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(read) => {
+                    read?;
+                }
+                Err(_) => {
+                    self.log_timeout("waiting for the preface");
+                    return Ok(());
+                }
+            }
+        }
+
+        if &self.buffer[..PREFACE.len()] != PREFACE {
+            return self.close(ErrorCode::ProtocolError).await;
+        }
+        self.buffer.advance(PREFACE.len());
+
+        self.idle_deadline = time::Instant::now() + self.config.idle_timeout;
+        self.run().await
+    }
+
+    async fn run(&mut self) -> io::Result<()> {
+        loop {
+            loop {
+                match Frame::parse(&self.buffer, DEFAULT_MAX_FRAME_SIZE) {
+                    Ok(Some((frame, used))) => {
+                        self.buffer.advance(used);
+                        match self.on_frame(frame) {
+                            Ok(()) => {}
+                            Err(H2Error::Stream(id, code)) => self.reset(id, code),
+                            Err(H2Error::Connection(code)) => return self.close(code).await,
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(code) => return self.close(code).await,
+                }
+            }
+
+            if let Err(code) = self.send_data() {
+                return self.close(code).await;
+            }
+            self.flush().await?;
+
+            if self.going_away && self.streams.is_empty() {
+                return Ok(());
+            }
+
+            let deadline = self.next_deadline();
+            tokio::select! {
+                read = self.stream.read_buf(&mut self.buffer) => {
+                    if 0 == read? {
+                        return Ok(());
+                    }
+                }
+                Some((id, chunk)) = self.chunks_rx.recv() => self.on_chunk(id, chunk),
+                _ = self.shutdown.triggered(), if !self.going_away => {
+                    self.queue(Frame::go_away(self.last_stream_id, ErrorCode::NoError));
+                    self.going_away = true;
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    if let Err(code) = self.on_deadline() {
+                        return self.close(code).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The earliest of the open streams' read deadlines and, with no
+    /// stream open, the idle deadline.
+    fn next_deadline(&self) -> Option<time::Instant> {
+        // With no stream open the client gets `idle_timeout` to start one,
+        // as between requests on HTTP/1.1.
+        let idle = (self.streams.is_empty() && !self.going_away).then_some(self.idle_deadline);
+        self.streams
+            .values()
+            .filter_map(|stream| stream.read_deadline)
+            .chain(idle)
+            .min()
+    }
+
+    /// Resets streams whose request didn't arrive in time and starts
+    /// closing an idle connection. A header block left unfinished takes the
+    /// whole connection down, since HPACK state can't skip it.
+    fn on_deadline(&mut self) -> Result<(), ErrorCode> {
+        let now = time::Instant::now();
+        if let Some(id) = self.continuation {
+            let deadline = self.streams.get(&id).and_then(|s| s.read_deadline);
+            if deadline.is_some_and(|d| d <= now) {
+                self.log_timeout("reading the request headers");
+                return Err(ErrorCode::NoError);
+            }
+        }
+
+        let expired: Vec<u32> = self
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.read_deadline.is_some_and(|d| d <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.logger.log(
+                LogLevel::Warning,
+                &format!(
+                    "Timed out reading the request on stream {} from {}, resetting it",
+                    id, self.ip
+                ),
+            );
+            self.remove_stream(id);
+            self.queue(Frame::rst_stream(id, ErrorCode::Cancel));
+        }
+
+        if self.streams.is_empty() && !self.going_away && self.idle_deadline <= now {
+            self.log_timeout("waiting for a stream");
+            self.queue(Frame::go_away(self.last_stream_id, ErrorCode::NoError));
+            self.going_away = true;
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> FrameResult {
+        if let Some(id) = self.continuation {
+            if frame.kind != FrameType::Continuation || frame.stream_id != id {
+                return Err(ErrorCode::ProtocolError.into());
+            }
+        }
+
+        match frame.kind {
+            FrameType::Data => self.on_data(frame),
+            FrameType::Headers => self.on_headers(frame),
+            FrameType::Continuation => self.on_continuation(frame),
+            FrameType::Priority => {
+                if frame.stream_id == 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                if frame.payload.len() != 5 {
+                    return Err(H2Error::Stream(frame.stream_id, ErrorCode::FrameSizeError));
+                }
+                Ok(())
+            }
+            FrameType::RstStream => {
+                if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                if frame.payload.len() != 4 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                self.remove_stream(frame.stream_id);
+                Ok(())
+            }
+            FrameType::Settings => self.on_settings(frame),
+            FrameType::PushPromise => Err(ErrorCode::ProtocolError.into()),
+            FrameType::Ping => {
+                if frame.stream_id != 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                if frame.payload.len() != 8 {
+                    return Err(ErrorCode::FrameSizeError.into());
+                }
+                if !frame.has_flag(flags::ACK) {
+                    self.queue(Frame::ping_ack(frame.payload));
+                }
+                Ok(())
+            }
+            FrameType::GoAway => {
+                if frame.stream_id != 0 {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                self.going_away = true;
+                Ok(())
+            }
+            FrameType::WindowUpdate => self.on_window_update(frame),
+            FrameType::Unknown(_) => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> FrameResult {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+
+        let mut fragment = frame.unpadded_payload()?;
+        if frame.has_flag(flags::PRIORITY) {
+            if fragment.len() < 5 {
+                return Err(ErrorCode::FrameSizeError.into());
+            }
+            fragment = &fragment[5..];
+        }
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // A second HEADERS frame carries trailers and has to end the stream.
+            if stream.remote_closed {
+                return Err(H2Error::Stream(id, ErrorCode::StreamClosed));
+            }
+            if !frame.has_flag(flags::END_STREAM) {
+                return Err(H2Error::Stream(id, ErrorCode::ProtocolError));
+            }
+        } else {
+            if id.is_multiple_of(2) || id <= self.last_stream_id {
+                return Err(ErrorCode::ProtocolError.into());
+            }
+            self.last_stream_id = id;
+            let mut stream = Stream::new(self.peer_initial_window);
+            stream.read_deadline = Some(time::Instant::now() + self.config.header_read_timeout);
+            self.streams.insert(id, stream);
+        }
+
+        let stream = self.streams.get_mut(&id).expect("stream was just inserted");
+        stream.header_block = fragment.to_vec();
+        stream.ends_with_headers = frame.has_flag(flags::END_STREAM);
+
+        if frame.has_flag(flags::END_HEADERS) {
+            self.finish_headers(id)
+        } else {
+            self.continuation = Some(id);
+            Ok(())
+        }
+    }
+
+    fn on_continuation(&mut self, frame: Frame) -> FrameResult {
+        if self.continuation != Some(frame.stream_id) {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+
+        let stream = self
+            .streams
+            .get_mut(&frame.stream_id)
+            .ok_or(ErrorCode::ProtocolError)?;
+        let max_block_size = MAX_HEADER_BLOCK_SIZE.min(self.config.max_request_size);
+        if stream.header_block.len() + frame.payload.len() > max_block_size {
+            return Err(ErrorCode::EnhanceYourCalm.into());
+        }
+        stream.header_block.extend_from_slice(&frame.payload);
+
+        if frame.has_flag(flags::END_HEADERS) {
+            self.continuation = None;
+            self.finish_headers(frame.stream_id)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish_headers(&mut self, id: u32) -> FrameResult {
+        let max_list_size = self.config.max_request_size;
+        let open_streams = self.streams.values().filter(|s| !s.end_sent).count();
+        let stream = self.streams.get_mut(&id).ok_or(ErrorCode::ProtocolError)?;
+        let block = std::mem::take(&mut stream.header_block);
+        let is_trailers = !stream.headers.is_empty();
+
+        let headers = match self.decoder.decode(&block, max_list_size) {
+            Ok(headers) => headers,
+            Err(HpackError::HeaderListTooLarge) => {
+                let response = Res::new(FrameError::HeadersTooLarge.response(), 431);
+                return self.respond(id, response);
+            }
+            Err(_) => return Err(ErrorCode::CompressionError.into()),
+        };
+
+        if !is_trailers {
+            if open_streams > MAX_CONCURRENT_STREAMS as usize {
+                return Err(H2Error::Stream(id, ErrorCode::RefusedStream));
+            }
+            stream.headers = headers;
+            stream.read_deadline = Some(time::Instant::now() + self.config.body_read_timeout);
+        }
+
+        if stream.ends_with_headers {
+            stream.remote_closed = true;
+            self.dispatch(id)?;
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: Frame) -> FrameResult {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+
+        // Flow control counts the whole payload, padding included. The
+        // connection window only reopens as bodies are handed off, so what
+        // a client can have buffered across its streams stays bounded.
+        let len = frame.payload.len();
+        if len as i64 > self.recv_window {
+            return Err(ErrorCode::FlowControlError.into());
+        }
+        self.recv_window -= len as i64;
+
+        let max_body = self.config.max_request_size;
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) if !stream.remote_closed => stream,
+            Some(_) => {
+                self.release(len);
+                return Err(H2Error::Stream(id, ErrorCode::StreamClosed));
+            }
+            None if id <= self.last_stream_id => {
+                self.release(len);
+                return Err(H2Error::Stream(id, ErrorCode::StreamClosed));
+            }
+            None => return Err(ErrorCode::ProtocolError.into()),
+        };
+
+        let data = frame.unpadded_payload()?;
+        let end_stream = frame.has_flag(flags::END_STREAM);
+        let responded = stream.responded;
+        if !responded {
+            stream.body.extend_from_slice(data);
+            stream.unreleased += len;
+        }
+        if end_stream {
+            stream.remote_closed = true;
+            stream.read_deadline = None;
+        } else if len > 0 {
+            Frame::window_update(id, len as u32).encode(&mut self.out);
+        }
+
+        // Padding counts too, or it could hold the window without ever
+        // making the body too large.
+        let too_large = stream.unreleased > max_body;
+        if responded {
+            // Dropped unread, so it needn't hold the window.
+            self.release(len);
+        } else if too_large {
+            self.take_body(id);
+            let response = Res::new(FrameError::BodyTooLarge.response(), 413);
+            return self.respond(id, response);
+        } else if end_stream {
+            self.dispatch(id)?;
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> FrameResult {
+        if frame.stream_id != 0 {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+        if frame.has_flag(flags::ACK) {
+            if !frame.payload.is_empty() {
+                return Err(ErrorCode::FrameSizeError.into());
+            }
+            return Ok(());
+        }
+
+        self.apply_settings(&frame.payload)?;
+        self.queue(Frame::settings_ack());
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> FrameResult {
+        for (id, value) in parse_settings(payload)? {
+            match id {
+                id if id == SettingId::EnablePush as u16 && value > 1 => {
+                    return Err(ErrorCode::ProtocolError.into());
+                }
+                id if id == SettingId::InitialWindowSize as u16 => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(ErrorCode::FlowControlError.into());
+                    }
+                    let delta = value - self.peer_initial_window;
+                    self.peer_initial_window = value;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(ErrorCode::FlowControlError.into());
+                        }
+                    }
+                }
+                id if id == SettingId::MaxFrameSize as u16 => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=16_777_215).contains(&value) {
+                        return Err(ErrorCode::ProtocolError.into());
+                    }
+                    self.peer_max_frame_size = value as usize;
+                }
+                // Our encoder never uses the dynamic table, so the peer's
+                // table size doesn't matter, and we never push.
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> FrameResult {
+        if frame.payload.len() != 4 {
+            return Err(ErrorCode::FrameSizeError.into());
+        }
+        let p = &frame.payload;
+        let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]]) & 0x7fff_ffff) as i64;
+
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(ErrorCode::ProtocolError.into());
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(ErrorCode::FlowControlError.into());
+            }
+            return Ok(());
+        }
+
+        if increment == 0 {
+            return Err(H2Error::Stream(frame.stream_id, ErrorCode::ProtocolError));
+        }
+        if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            stream.send_window += increment;
+            if stream.send_window > MAX_WINDOW_SIZE {
+                return Err(H2Error::Stream(
+                    frame.stream_id,
+                    ErrorCode::FlowControlError,
+                ));
+            }
+        } else if frame.stream_id > self.last_stream_id {
+            return Err(ErrorCode::ProtocolError.into());
+        }
+        Ok(())
+    }
+
+    /// Turns a complete request into a call to the handler.
+    fn dispatch(&mut self, id: u32) -> FrameResult {
+        self.idle_deadline = time::Instant::now() + self.config.idle_timeout;
+        let body = self.take_body(id);
+        let stream = self.streams.get_mut(&id).ok_or(ErrorCode::ProtocolError)?;
+        stream.read_deadline = None;
+        let headers = std::mem::take(&mut stream.headers);
+
+        let request =
+            build_request(headers, body).ok_or(H2Error::Stream(id, ErrorCode::ProtocolError))?;
+        self.answer(id, request)
+    }
+
+    fn answer(&mut self, id: u32, request: HttpRequest) -> FrameResult {
+        let start_time = Instant::now();
+        let method = request.method;
+        let path = request.path.clone();
+
+        let mut response = self.http_handler.handle_request(request, self.tls.clone());
+        if let (Some(_), Some(hsts)) = (&self.tls, self.config.hsts) {
+            response.set_header("Strict-Transport-Security", &hsts.header_value());
+        }
+
+        Logger::log_http(&RequestResponse {
+            method,
+            path,
+            ip: self.ip.clone(),
+            server_name: self.tls.as_ref().and_then(|tls| tls.server_name.clone()),
+            alpn_protocol: self.tls.as_ref().and_then(|tls| tls.alpn_protocol.clone()),
+            status: response.status,
+            duration: start_time.elapsed(),
+        });
+
+        self.respond(id, response)
+    }
+
+    /// Queues the response HEADERS and its body for `id`.
+    fn respond(&mut self, id: u32, response: Res) -> FrameResult {
+        let head_end = response
+            .buffer
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(H2Error::Stream(id, ErrorCode::InternalError))?;
+        let head = String::from_utf8_lossy(&response.buffer[..head_end]).into_owned();
+        let body = response.buffer[head_end + 4..].to_vec();
+        let headers = response_headers(&head);
+
+        let end_stream = body.is_empty() && response.stream.is_none();
+        let block = self.encoder.encode(&headers);
+        self.queue_header_block(id, block, end_stream);
+
+        let stream = self.streams.get_mut(&id).ok_or(ErrorCode::ProtocolError)?;
+        stream.responded = true;
+        stream.pending = body;
+        stream.sent = 0;
+        stream.finished = response.stream.is_none();
+        stream.end_sent = end_stream;
+
+        if let Some(mut body) = response.stream {
+            let permits = Arc::new(Semaphore::new(1));
+            stream.body_permits = Some(Arc::clone(&permits));
+            stream.awaiting_chunk = true;
+
+            let chunks = self.chunks_tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    match permits.acquire().await {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => return,
+                    }
+                    let chunk = body.next_chunk().await;
+                    let done = chunk.is_none();
+                    if chunks.send((id, chunk)).is_err() || done {
+                        return;
+                    }
+                }
+            });
+        }
+
+        self.cleanup(id);
+        Ok(())
+    }
+
+    fn on_chunk(&mut self, id: u32, chunk: Option<Vec<u8>>) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.awaiting_chunk = false;
+            match chunk {
+                Some(chunk) => {
+                    stream.pending.drain(..stream.sent);
+                    stream.sent = 0;
+                    stream.pending.extend_from_slice(&chunk);
+                }
+                None => {
+                    stream.finished = true;
+                    stream.body_permits = None;
+                }
+            }
+        }
+    }
+
+    /// Writes as much pending body data as the flow control windows allow.
+    fn send_data(&mut self) -> Result<(), ErrorCode> {
+        let ids: Vec<u32> = self.streams.keys().copied().collect();
+        for id in ids {
+            let max_frame = self.peer_max_frame_size as i64;
+            let stream = match self.streams.get_mut(&id) {
+                Some(stream) if stream.responded && !stream.end_sent => stream,
+                _ => continue,
+            };
+
+            while stream.unsent() > 0 && self.send_window > 0 && stream.send_window > 0 {
+                let n = (stream.unsent() as i64)
+                    .min(self.send_window)
+                    .min(stream.send_window)
+                    .min(max_frame) as usize;
+                let end_stream = stream.finished && n == stream.unsent();
+                let data = stream.pending[stream.sent..stream.sent + n].to_vec();
+
+                Frame::data(id, data, end_stream).encode(&mut self.out);
+                stream.sent += n;
+                stream.send_window -= n as i64;
+                self.send_window -= n as i64;
+                stream.end_sent = end_stream;
+            }
+
+            if stream.unsent() == 0 && stream.finished && !stream.end_sent {
+                Frame::data(id, Vec::new(), true).encode(&mut self.out);
+                stream.end_sent = true;
+            }
+
+            if stream.unsent() == 0 && !stream.finished && !stream.awaiting_chunk {
+                if let Some(permits) = &stream.body_permits {
+                    stream.awaiting_chunk = true;
+                    permits.add_permits(1);
+                }
+            }
+
+            self.cleanup(id);
+        }
+        Ok(())
+    }
+
+    /// Forgets a stream once both sides are done with it.
+    fn cleanup(&mut self, id: u32) {
+        if self
+            .streams
+            .get(&id)
+            .is_some_and(|s| s.end_sent && s.remote_closed)
+        {
+            self.remove_stream(id);
+            // A long response shouldn't count against the idle time after it.
+            self.idle_deadline = time::Instant::now() + self.config.idle_timeout;
+        }
+    }
+
+    /// Takes the body buffered for `id`, reopening the connection window
+    /// it held.
+    fn take_body(&mut self, id: u32) -> Vec<u8> {
+        let (body, held) = match self.streams.get_mut(&id) {
+            Some(stream) => (
+                std::mem::take(&mut stream.body),
+                std::mem::take(&mut stream.unreleased),
+            ),
+            None => return Vec::new(),
+        };
+        self.release(held);
+        body
+    }
+
+    /// Forgets `id`, reopening the connection window its body held.
+    fn remove_stream(&mut self, id: u32) {
+        if let Some(stream) = self.streams.remove(&id) {
+            self.release(stream.unreleased);
+        }
+    }
+
+    fn release(&mut self, len: usize) {
+        if len > 0 {
+            self.recv_window += len as i64;
+            self.queue(Frame::window_update(0, len as u32));
+        }
+    }
+
+    fn queue_header_block(&mut self, id: u32, block: Vec<u8>, end_stream: bool) {
+        let mut fragments = block.chunks(self.peer_max_frame_size).peekable();
+        let first = fragments.next().unwrap_or(&[]).to_vec();
+
+        let mut first_flags = if end_stream { flags::END_STREAM } else { 0 };
+        if fragments.peek().is_none() {
+            first_flags |= flags::END_HEADERS;
+        }
+        self.queue(Frame::new(FrameType::Headers, first_flags, id, first));
+
+        while let Some(fragment) = fragments.next() {
+            let flags = if fragments.peek().is_none() {
+                flags::END_HEADERS
+            } else {
+                0
+            };
+            self.queue(Frame::new(
+                FrameType::Continuation,
+                flags,
+                id,
+                fragment.to_vec(),
+            ));
+        }
+    }
+
+    fn reset(&mut self, id: u32, code: ErrorCode) {
+        self.logger.log(
+            LogLevel::Warning,
+            &format!(
+                "Resetting HTTP/2 stream {} from {}: {:?}",
+                id, self.ip, code
+            ),
+        );
+        self.remove_stream(id);
+        if self.continuation == Some(id) {
+            self.continuation = None;
+        }
+        self.queue(Frame::rst_stream(id, code));
+    }
+
+    async fn close(&mut self, code: ErrorCode) -> io::Result<()> {
+        if code != ErrorCode::NoError {
+            self.logger.log(
+                LogLevel::Warning,
+                &format!("Closing HTTP/2 connection from {}: {:?}", self.ip, code),
+            );
+        }
+        self.queue(Frame::go_away(self.last_stream_id, code));
+        self.flush().await
+    }
+
+    fn queue(&mut self, frame: Frame) {
+        frame.encode(&mut self.out);
+    }
+
+    /// Writes out what's been queued, failing with `TimedOut` if the client
+    /// doesn't take it within `write_timeout`.
+    async fn flush(&mut self) -> io::Result<()> {
+        let limit = self.config.write_timeout;
+        if !self.out.is_empty() {
+            within(limit, self.stream.write_all(&self.out)).await?;
+            self.out.clear();
+        }
+        within(limit, self.stream.flush()).await
+    }
+
+    fn log_timeout(&self, waiting_on: &str) {
+        self.logger.log(
+            LogLevel::Warning,
+            &format!(
+                "Timed out {} from {}, closing the connection",
+                waiting_on, self.ip
+            ),
+        );
+    }
+}
+
+/// Maps decoded request headers onto an `HttpRequest`, checking the pseudo
+/// header rules from RFC 9113 section 8.3.
+fn build_request(fields: HeaderList, body: Vec<u8>) -> Option<HttpRequest> {
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut seen_regular = false;
+
+    for (name, value) in fields {
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return None;
+        }
+
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if seen_regular {
+                return None;
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                "scheme" => continue,
+                _ => return None,
+            };
+            if slot.replace(value).is_some() {
+                return None;
+            }
+            continue;
+        }
+
+        seen_regular = true;
+        if CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers") {
+            return None;
+        }
+
+        // Cookies may be split across fields, and have to be joined with
+        // "; " rather than the usual ", ".
+        let separator = if name == "cookie" { "; " } else { ", " };
+        headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(separator);
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    if let Some(authority) = authority {
+        headers.entry("host".to_string()).or_insert(authority);
+    }
+
+    let method = HttpMethod::from_str(&method?).ok()?;
+    let path = path.filter(|p| !p.is_empty())?;
+
+    Some(HttpRequest::from_parts(
+        method,
+        path,
+        HttpVersion::Http2,
+        headers,
+        body,
+    ))
+}
+
+/// Converts the head of a serialised HTTP/1.1 response into an HTTP/2
+/// header list, dropping hop-by-hop headers.
+fn response_headers(head: &str) -> HeaderList {
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("500");
+
+    let mut headers = vec![(":status".to_string(), status.to_string())];
+    for line in lines {
+        if let Some((key, value)) = line.split_once(':') {
+            let key = key.trim().to_lowercase();
+            if !CONNECTION_HEADERS.contains(&key.as_str()) {
+                headers.push((key, value.trim().to_string()));
+            }
+        }
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{MiddlewareHandler, RouteManager};
+    use std::{collections::HashMap, time::Duration};
+    use tokio::io::{duplex, DuplexStream};
+
+    fn config() -> Config {
+        Config {
+            header_read_timeout: Duration::from_millis(200),
+            body_read_timeout: Duration::from_millis(300),
+            idle_timeout: Duration::from_millis(400),
+            ..Config::default()
+        }
+    }
+
+    /// Serves a connection in the background, returning the client's end
+    /// with the preface and an empty SETTINGS frame already sent.
+    async fn connect(config: Config) -> DuplexStream {
+        let (mut client, server) = duplex(1 << 20);
+        let handler = HttpHandler::new(
+            Arc::new(RouteManager::new()),
+            Arc::new(MiddlewareHandler::new()),
+            Arc::new(HashMap::new()),
+        );
+        let connection = Http2Connection::new(
+            server,
+            BytesMut::new(),
+            Arc::new(handler),
+            Arc::new(config),
+            "test".to_string(),
+            None,
+        );
+        tokio::spawn(connection.serve());
+
+        let mut out = PREFACE.to_vec();
+        Frame::settings(&[]).encode(&mut out);
+        client.write_all(&out).await.unwrap();
+        client
+    }
+
+    fn headers(id: u32, flags: u8) -> Vec<u8> {
+        let block = Encoder::new().encode(&[
+            (":method".to_string(), "POST".to_string()),
+            (":scheme".to_string(), "http".to_string()),
+            (":path".to_string(), "/".to_string()),
+            (":authority".to_string(), "localhost".to_string()),
+        ]);
+        let mut out = Vec::new();
+        Frame::new(FrameType::Headers, flags, id, block).encode(&mut out);
+        out
+    }
+
+    /// Reads frames until one of `kind` arrives, or `None` if the
+    /// connection closes or `limit` passes first.
+    async fn wait_for(
+        client: &mut (impl AsyncRead + Unpin),
+        kind: FrameType,
+        limit: Duration,
+    ) -> Option<Frame> {
+        let mut buffer = BytesMut::new();
+        let deadline = time::Instant::now() + limit;
+        loop {
+            while let Some((frame, used)) = Frame::parse(&buffer, 1 << 24).unwrap() {
+                buffer.advance(used);
+                if frame.kind == kind {
+                    return Some(frame);
+                }
+            }
+            match time::timeout_at(deadline, client.read_buf(&mut buffer)).await {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return None,
+                Ok(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// Collects `(stream, increment)` from the WINDOW_UPDATE frames that
+    /// arrive within `limit`.
+    async fn window_updates(client: &mut DuplexStream, limit: Duration) -> Vec<(u32, u32)> {
+        let mut buffer = BytesMut::new();
+        let deadline = time::Instant::now() + limit;
+        while let Ok(Ok(read)) = time::timeout_at(deadline, client.read_buf(&mut buffer)).await {
+            if read == 0 {
+                break;
+            }
+        }
+
+        let mut updates = Vec::new();
+        while let Some((frame, used)) = Frame::parse(&buffer, 1 << 24).unwrap() {
+            buffer.advance(used);
+            if frame.kind == FrameType::WindowUpdate {
+                let increment = u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+                updates.push((frame.stream_id, increment));
+            }
+        }
+        updates
+    }
+
+    #[tokio::test]
+    async fn connection_window_reopens_once_the_body_is_handed_off() {
+        let config = Config {
+            max_request_size: 100_000,
+            ..config()
+        };
+        let mut client = connect(config).await;
+        let mut out = headers(1, flags::END_HEADERS);
+        Frame::new(FrameType::Data, 0, 1, vec![0; 16_384]).encode(&mut out);
+        client.write_all(&out).await.unwrap();
+
+        // The stream may go on sending, but the connection window stays
+        // spent while the body is buffered.
+        let limit = Duration::from_millis(100);
+        assert_eq!(
+            window_updates(&mut client, limit).await,
+            [(0, 100_001 - 65_535), (1, 16_384)]
+        );
+
+        let mut end = Vec::new();
+        Frame::new(FrameType::Data, flags::END_STREAM, 1, Vec::new()).encode(&mut end);
+        client.write_all(&end).await.unwrap();
+        assert_eq!(window_updates(&mut client, limit).await, [(0, 16_384)]);
+    }
+
+    #[tokio::test]
+    async fn stalled_body_resets_the_stream() {
+        let mut client = connect(config()).await;
+        client
+            .write_all(&headers(1, flags::END_HEADERS))
+            .await
+            .unwrap();
+
+        let reset = wait_for(&mut client, FrameType::RstStream, Duration::from_secs(2))
+            .await
+            .expect("stream reset");
+        assert_eq!(reset.stream_id, 1);
+        assert_eq!(reset.payload, (ErrorCode::Cancel as u32).to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn unfinished_header_block_closes_the_connection() {
+        let mut client = connect(config()).await;
+        client.write_all(&headers(1, 0)).await.unwrap();
+
+        assert!(
+            wait_for(&mut client, FrameType::GoAway, Duration::from_secs(2))
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn pings_do_not_hold_an_idle_connection_open() {
+        let (mut reader, mut writer) = tokio::io::split(connect(config()).await);
+        tokio::spawn(async move {
+            let mut ping = Vec::new();
+            Frame::new(FrameType::Ping, 0, 0, vec![0; 8]).encode(&mut ping);
+            while writer.write_all(&ping).await.is_ok() {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+
+        let started = time::Instant::now();
+        assert!(
+            wait_for(&mut reader, FrameType::GoAway, Duration::from_secs(2))
+                .await
+                .is_some()
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
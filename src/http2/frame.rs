@@ -0,0 +1,228 @@
+pub const FRAME_HEADER_LEN: usize = 9;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+pub const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+pub const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+
+pub mod flags {
+    pub const END_STREAM: u8 = 0x1;
+    pub const ACK: u8 = 0x1;
+    pub const END_HEADERS: u8 = 0x4;
+    pub const PADDED: u8 = 0x8;
+    pub const PRIORITY: u8 = 0x20;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    Unknown(u8),
+}
+
+impl From<u8> for FrameType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0 => FrameType::Data,
+            0x1 => FrameType::Headers,
+            0x2 => FrameType::Priority,
+            0x3 => FrameType::RstStream,
+            0x4 => FrameType::Settings,
+            0x5 => FrameType::PushPromise,
+            0x6 => FrameType::Ping,
+            0x7 => FrameType::GoAway,
+            0x8 => FrameType::WindowUpdate,
+            0x9 => FrameType::Continuation,
+            other => FrameType::Unknown(other),
+        }
+    }
+}
+
+impl From<FrameType> for u8 {
+    fn from(value: FrameType) -> Self {
+        match value {
+            FrameType::Data => 0x0,
+            FrameType::Headers => 0x1,
+            FrameType::Priority => 0x2,
+            FrameType::RstStream => 0x3,
+            FrameType::Settings => 0x4,
+            FrameType::PushPromise => 0x5,
+            FrameType::Ping => 0x6,
+            FrameType::GoAway => 0x7,
+            FrameType::WindowUpdate => 0x8,
+            FrameType::Continuation => 0x9,
+            FrameType::Unknown(other) => other,
+        }
+    }
+}
+
+/// RFC 9113 section 7 error codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    SettingsTimeout = 0x4,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    Cancel = 0x8,
+    CompressionError = 0x9,
+    ConnectError = 0xa,
+    EnhanceYourCalm = 0xb,
+    InadequateSecurity = 0xc,
+    Http11Required = 0xd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingId {
+    HeaderTableSize = 0x1,
+    EnablePush = 0x2,
+    MaxConcurrentStreams = 0x3,
+    InitialWindowSize = 0x4,
+    MaxFrameSize = 0x5,
+    MaxHeaderListSize = 0x6,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameType,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: FrameType, flags: u8, stream_id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            flags,
+            stream_id,
+            payload,
+        }
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Reads one frame from the start of `buffer`. Returns `Ok(None)` until
+    /// the whole frame has arrived, and the number of bytes it used.
+    pub fn parse(
+        buffer: &[u8],
+        max_frame_size: usize,
+    ) -> Result<Option<(Frame, usize)>, ErrorCode> {
+        if buffer.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([0, buffer[0], buffer[1], buffer[2]]) as usize;
+        if len > max_frame_size {
+            return Err(ErrorCode::FrameSizeError);
+        }
+        if buffer.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let stream_id =
+            u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]) & 0x7fff_ffff;
+        let frame = Frame {
+            kind: FrameType::from(buffer[3]),
+            flags: buffer[4],
+            stream_id,
+            payload: buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec(),
+        };
+        Ok(Some((frame, FRAME_HEADER_LEN + len)))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let len = (self.payload.len() as u32).to_be_bytes();
+        out.extend_from_slice(&len[1..]);
+        out.push(self.kind.into());
+        out.push(self.flags);
+        out.extend_from_slice(&(self.stream_id & 0x7fff_ffff).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+    }
+
+    /// Strips padding from DATA and HEADERS payloads.
+    pub fn unpadded_payload(&self) -> Result<&[u8], ErrorCode> {
+        if !self.has_flag(flags::PADDED) {
+            return Ok(&self.payload);
+        }
+        let pad_len = *self.payload.first().ok_or(ErrorCode::FrameSizeError)? as usize;
+        if pad_len >= self.payload.len() {
+            return Err(ErrorCode::ProtocolError);
+        }
+        Ok(&self.payload[1..self.payload.len() - pad_len])
+    }
+
+    pub fn settings(settings: &[(SettingId, u32)]) -> Self {
+        let mut payload = Vec::with_capacity(settings.len() * 6);
+        for (id, value) in settings {
+            payload.extend_from_slice(&(*id as u16).to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        Frame::new(FrameType::Settings, 0, 0, payload)
+    }
+
+    pub fn settings_ack() -> Self {
+        Frame::new(FrameType::Settings, flags::ACK, 0, Vec::new())
+    }
+
+    pub fn ping_ack(payload: Vec<u8>) -> Self {
+        Frame::new(FrameType::Ping, flags::ACK, 0, payload)
+    }
+
+    pub fn window_update(stream_id: u32, increment: u32) -> Self {
+        Frame::new(
+            FrameType::WindowUpdate,
+            0,
+            stream_id,
+            increment.to_be_bytes().to_vec(),
+        )
+    }
+
+    pub fn rst_stream(stream_id: u32, code: ErrorCode) -> Self {
+        Frame::new(
+            FrameType::RstStream,
+            0,
+            stream_id,
+            (code as u32).to_be_bytes().to_vec(),
+        )
+    }
+
+    pub fn go_away(last_stream_id: u32, code: ErrorCode) -> Self {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        Frame::new(FrameType::GoAway, 0, 0, payload)
+    }
+
+    pub fn data(stream_id: u32, data: Vec<u8>, end_stream: bool) -> Self {
+        let flags = if end_stream { flags::END_STREAM } else { 0 };
+        Frame::new(FrameType::Data, flags, stream_id, data)
+    }
+}
+
+/// Parses a SETTINGS payload into raw (identifier, value) pairs. Unknown
+/// identifiers are kept so the caller can ignore them.
+pub fn parse_settings(payload: &[u8]) -> Result<Vec<(u16, u32)>, ErrorCode> {
+    if !payload.len().is_multiple_of(6) {
+        return Err(ErrorCode::FrameSizeError);
+    }
+    Ok(payload
+        .chunks_exact(6)
+        .map(|s| {
+            (
+                u16::from_be_bytes([s[0], s[1]]),
+                u32::from_be_bytes([s[2], s[3], s[4], s[5]]),
+            )
+        })
+        .collect())
+}
@@ -0,0 +1,586 @@
+use std::collections::VecDeque;
+
+use super::huffman;
+
+/// RFC 7541 Appendix A. Index 0 is unused, entries start at 1.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Per-entry overhead counted towards the dynamic table size.
+const ENTRY_OVERHEAD: usize = 32;
+
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpackError {
+    InvalidIndex,
+    InvalidInteger,
+    InvalidHuffman,
+    InvalidTableSizeUpdate,
+    Truncated,
+    HeaderListTooLarge,
+}
+
+pub type HeaderList = Vec<(String, String)>;
+
+#[derive(Debug)]
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    /// Upper bound we advertised in SETTINGS_HEADER_TABLE_SIZE.
+    settings_max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Decoder {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size,
+            settings_max_size: max_size,
+        }
+    }
+
+    /// Decodes a complete header block. `max_list_size` bounds the decoded
+    /// size the same way SETTINGS_MAX_HEADER_LIST_SIZE does.
+    ///
+    /// Once the list is over that size no more fields are kept, so a block
+    /// repeating a large indexed entry can't make us allocate far more than
+    /// it is long. The rest of the block is still walked for its table
+    /// inserts, keeping the dynamic table in sync with the peer's encoder.
+    pub fn decode(&mut self, block: &[u8], max_list_size: usize) -> Result<HeaderList, HpackError> {
+        let mut headers = Vec::new();
+        let mut list_size: usize = 0;
+        let mut pos = 0;
+        let mut seen_field = false;
+
+        while pos < block.len() {
+            let byte = block[pos];
+            let too_large = list_size > max_list_size;
+
+            let field = if byte & 0x80 != 0 {
+                let index = decode_integer(block, &mut pos, 7)?;
+                if too_large {
+                    list_size += self.field_size(index)?;
+                    None
+                } else {
+                    Some(self.get(index)?)
+                }
+            } else if byte & 0x40 != 0 {
+                let (name, value) = self.decode_literal(block, &mut pos, 6)?;
+                self.insert(name.clone(), value.clone());
+                Some((name, value))
+            } else if byte & 0x20 != 0 {
+                // Size updates are only allowed before the first field.
+                if seen_field {
+                    return Err(HpackError::InvalidTableSizeUpdate);
+                }
+                let size = decode_integer(block, &mut pos, 5)?;
+                if size > self.settings_max_size {
+                    return Err(HpackError::InvalidTableSizeUpdate);
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // Literal without indexing and never indexed share a layout.
+                Some(self.decode_literal(block, &mut pos, 4)?)
+            };
+
+            seen_field = true;
+            if let Some((name, value)) = field {
+                list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+                if list_size <= max_list_size {
+                    headers.push((name, value));
+                }
+            }
+        }
+
+        if list_size > max_list_size {
+            return Err(HpackError::HeaderListTooLarge);
+        }
+
+        Ok(headers)
+    }
+
+    fn decode_literal(
+        &self,
+        block: &[u8],
+        pos: &mut usize,
+        prefix: u8,
+    ) -> Result<(String, String), HpackError> {
+        let index = decode_integer(block, pos, prefix)?;
+        let name = if index == 0 {
+            decode_string(block, pos)?
+        } else {
+            self.get(index)?.0
+        };
+        let value = decode_string(block, pos)?;
+        Ok((name, value))
+    }
+
+    fn get(&self, index: usize) -> Result<(String, String), HpackError> {
+        if index == 0 {
+            return Err(HpackError::InvalidIndex);
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.to_string(), value.to_string()));
+        }
+        self.table
+            .get(index - STATIC_TABLE.len() - 1)
+            .cloned()
+            .ok_or(HpackError::InvalidIndex)
+    }
+
+    /// The size an indexed field adds to the header list, without copying
+    /// it out of the table.
+    fn field_size(&self, index: usize) -> Result<usize, HpackError> {
+        if index == 0 {
+            return Err(HpackError::InvalidIndex);
+        }
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok(name.len() + value.len() + ENTRY_OVERHEAD);
+        }
+        self.table
+            .get(index - STATIC_TABLE.len() - 1)
+            .map(|(name, value)| name.len() + value.len() + ENTRY_OVERHEAD)
+            .ok_or(HpackError::InvalidIndex)
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        let entry_size = name.len() + value.len() + ENTRY_OVERHEAD;
+        if entry_size > self.max_size {
+            // An entry larger than the table empties it and isn't added.
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+        self.size += entry_size;
+        self.table.push_front((name, value));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes header blocks without ever touching the dynamic table, so the
+/// peer's table size settings don't need tracking. Static table matches are
+/// still used where they exist.
+#[derive(Debug, Default)]
+pub struct Encoder;
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn encode(&self, headers: &[(String, String)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (name, value) in headers {
+            if let Some(index) = STATIC_TABLE
+                .iter()
+                .position(|(n, v)| *n == name && *v == value)
+            {
+                encode_integer(&mut block, index + 1, 7, 0x80);
+                continue;
+            }
+
+            match STATIC_TABLE.iter().position(|(n, _)| *n == name) {
+                Some(index) => encode_integer(&mut block, index + 1, 4, 0x00),
+                None => {
+                    block.push(0x00);
+                    encode_string(&mut block, name);
+                }
+            }
+            encode_string(&mut block, value);
+        }
+        block
+    }
+}
+
+fn decode_integer(block: &[u8], pos: &mut usize, prefix: u8) -> Result<usize, HpackError> {
+    let mask = (1u16 << prefix) as usize - 1;
+    let first = *block.get(*pos).ok_or(HpackError::Truncated)? as usize & mask;
+    *pos += 1;
+    if first < mask {
+        return Ok(first);
+    }
+
+    let mut value = mask;
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*pos).ok_or(HpackError::Truncated)?;
+        *pos += 1;
+        if shift > 28 {
+            return Err(HpackError::InvalidInteger);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_string(block: &[u8], pos: &mut usize) -> Result<String, HpackError> {
+    let huffman = *block.get(*pos).ok_or(HpackError::Truncated)? & 0x80 != 0;
+    let len = decode_integer(block, pos, 7)?;
+    let end = pos.checked_add(len).ok_or(HpackError::Truncated)?;
+    let raw = block.get(*pos..end).ok_or(HpackError::Truncated)?;
+    *pos = end;
+
+    let bytes = if huffman {
+        huffman::decode(raw)?
+    } else {
+        raw.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix: u8, flags: u8) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        block.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_integer(block, value.len(), 7, 0x00);
+    block.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn list(fields: &[(&str, &str)]) -> HeaderList {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// A header block in hex, the fields it decodes to and the dynamic table
+    /// size after it.
+    type Step<'a> = (&'a str, &'a [(&'a str, &'a str)], usize);
+
+    /// Decodes each block in turn on one decoder, checking the fields and
+    /// the dynamic table size after each.
+    fn check(decoder: &mut Decoder, steps: &[Step]) {
+        for (block, fields, table_size) in steps {
+            assert_eq!(decoder.decode(&hex(block), 1 << 16), Ok(list(fields)));
+            assert_eq!(decoder.size, *table_size);
+        }
+    }
+
+    // RFC 7541 appendix C.1.
+    #[test]
+    fn integer_representation() {
+        for (value, prefix, encoded) in [(10, 5, "0a"), (1337, 5, "1f9a0a"), (42, 8, "2a")] {
+            let mut block = Vec::new();
+            encode_integer(&mut block, value, prefix, 0);
+            assert_eq!(block, hex(encoded));
+            assert_eq!(decode_integer(&block, &mut 0, prefix), Ok(value));
+        }
+    }
+
+    // RFC 7541 appendix C.2.
+    #[test]
+    fn header_field_representations() {
+        let mut decoder = Decoder::default();
+        check(
+            &mut decoder,
+            &[(
+                "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+                &[("custom-key", "custom-header")],
+                55,
+            )],
+        );
+
+        let mut decoder = Decoder::default();
+        check(
+            &mut decoder,
+            &[
+                (
+                    "040c 2f73 616d 706c 652f 7061 7468",
+                    &[(":path", "/sample/path")],
+                    0,
+                ),
+                (
+                    "1008 7061 7373 776f 7264 0673 6563 7265 74",
+                    &[("password", "secret")],
+                    0,
+                ),
+                ("82", &[(":method", "GET")], 0),
+            ],
+        );
+    }
+
+    const FIRST_REQUEST: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+    ];
+    const SECOND_REQUEST: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "http"),
+        (":path", "/"),
+        (":authority", "www.example.com"),
+        ("cache-control", "no-cache"),
+    ];
+    const THIRD_REQUEST: &[(&str, &str)] = &[
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":path", "/index.html"),
+        (":authority", "www.example.com"),
+        ("custom-key", "custom-value"),
+    ];
+
+    // RFC 7541 appendix C.3.
+    #[test]
+    fn requests_without_huffman() {
+        check(
+            &mut Decoder::default(),
+            &[
+                (
+                    "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                    FIRST_REQUEST,
+                    57,
+                ),
+                ("8286 84be 5808 6e6f 2d63 6163 6865", SECOND_REQUEST, 110),
+                (
+                    "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+                    THIRD_REQUEST,
+                    164,
+                ),
+            ],
+        );
+    }
+
+    // RFC 7541 appendix C.4.
+    #[test]
+    fn requests_with_huffman() {
+        check(
+            &mut Decoder::default(),
+            &[
+                (
+                    "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                    FIRST_REQUEST,
+                    57,
+                ),
+                ("8286 84be 5886 a8eb 1064 9cbf", SECOND_REQUEST, 110),
+                (
+                    "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+                    THIRD_REQUEST,
+                    164,
+                ),
+            ],
+        );
+    }
+
+    const FIRST_RESPONSE: &[(&str, &str)] = &[
+        (":status", "302"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const SECOND_RESPONSE: &[(&str, &str)] = &[
+        (":status", "307"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+        ("location", "https://www.example.com"),
+    ];
+    const THIRD_RESPONSE: &[(&str, &str)] = &[
+        (":status", "200"),
+        ("cache-control", "private"),
+        ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+        ("location", "https://www.example.com"),
+        ("content-encoding", "gzip"),
+        (
+            "set-cookie",
+            "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+        ),
+    ];
+
+    // RFC 7541 appendix C.5, with a 256 byte table that has to evict.
+    #[test]
+    fn responses_without_huffman() {
+        check(
+            &mut Decoder::new(256),
+            &[
+                (
+                    "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230
+                     3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65
+                     7861 6d70 6c65 2e63 6f6d",
+                    FIRST_RESPONSE,
+                    222,
+                ),
+                ("4803 3330 37c1 c0bf", SECOND_RESPONSE, 222),
+                (
+                    "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220
+                     474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157
+                     454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076
+                     6572 7369 6f6e 3d31",
+                    THIRD_RESPONSE,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    // RFC 7541 appendix C.6.
+    #[test]
+    fn responses_with_huffman() {
+        check(
+            &mut Decoder::new(256),
+            &[
+                (
+                    "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0
+                     82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+                    FIRST_RESPONSE,
+                    222,
+                ),
+                ("4883 640e ffc1 c0bf", SECOND_RESPONSE, 222),
+                (
+                    "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b
+                     d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27
+                     0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+                    THIRD_RESPONSE,
+                    215,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn encoded_blocks_decode_to_the_same_list() {
+        let headers = list(THIRD_RESPONSE);
+        let block = Encoder::new().encode(&headers);
+        assert_eq!(Decoder::default().decode(&block, 1 << 16), Ok(headers));
+        assert_eq!(Encoder::new().encode(&list(&[(":method", "GET")])), [0x82]);
+    }
+
+    #[test]
+    fn invalid_blocks_are_rejected() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.decode(&[0x80], 1 << 16),
+            Err(HpackError::InvalidIndex)
+        );
+        assert_eq!(
+            decoder.decode(&[0xbe], 1 << 16),
+            Err(HpackError::InvalidIndex)
+        );
+        assert_eq!(
+            decoder.decode(&hex("400a 6375"), 1 << 16),
+            Err(HpackError::Truncated)
+        );
+        // A table size update after the first field.
+        assert_eq!(
+            decoder.decode(&[0x82, 0x20], 1 << 16),
+            Err(HpackError::InvalidTableSizeUpdate)
+        );
+        // One over the advertised 4096.
+        assert_eq!(
+            decoder.decode(&hex("3fe2 1f"), 1 << 16),
+            Err(HpackError::InvalidTableSizeUpdate)
+        );
+        assert_eq!(
+            decoder.decode(&hex("82 86 84"), 60),
+            Err(HpackError::HeaderListTooLarge)
+        );
+    }
+}
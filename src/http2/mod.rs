@@ -0,0 +1,8 @@
+mod connection;
+pub mod frame;
+pub mod hpack;
+mod huffman;
+mod upgrade;
+
+pub use connection::{Http2Connection, PREFACE};
+pub use upgrade::upgrade_settings;
@@ -0,0 +1,23 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use crate::http::{HttpRequest, HttpVersion};
+
+/// Checks whether `request` asks to switch to HTTP/2 over cleartext, as
+/// described in RFC 7540 section 3.2. Returns the decoded SETTINGS payload
+/// from `HTTP2-Settings` if it does.
+pub fn upgrade_settings(request: &HttpRequest) -> Option<Vec<u8>> {
+    if request.version != HttpVersion::Http11 {
+        return None;
+    }
+
+    if !request.has_token("upgrade", "h2c")
+        || !request.has_token("connection", "upgrade")
+        || !request.has_token("connection", "http2-settings")
+    {
+        return None;
+    }
+
+    // Some clients pad the value even though the token68 form shouldn't be.
+    let settings = request.headers.get("http2-settings")?.trim();
+    URL_SAFE_NO_PAD.decode(settings.trim_end_matches('=')).ok()
+}
@@ -1,9 +1,20 @@
+pub mod acme;
 pub mod config;
 pub mod connection;
 pub mod http;
+pub mod http2;
+pub mod limit;
+pub mod listener;
 pub mod logger;
 pub mod server;
+pub mod shutdown;
+pub mod stream;
+pub mod tls;
+pub mod websocket;
 
 pub use config::{Config, EnvValidator}; // Export both
+pub use limit::OverloadPolicy;
+pub use listener::Bind;
 pub use logger::Logger;
-pub use server::Server;
+pub use server::{Server, ServerHandle};
+pub use shutdown::Shutdown;
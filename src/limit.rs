@@ -0,0 +1,77 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// What happens to clients connecting while `max_connections` are open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Stop accepting until a connection closes, leaving new clients
+    /// queued in the kernel's listen backlog.
+    #[default]
+    Wait,
+    /// Accept and answer with a 503, asking the client to retry after
+    /// `retry_after`.
+    Reject { retry_after: Duration },
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wait" => Ok(Self::Wait),
+            "reject" => Ok(Self::Reject {
+                retry_after: Duration::from_secs(1),
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Caps how many connections are served at once, shared by every listener.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionLimit {
+    permits: Option<Arc<Semaphore>>,
+    policy: OverloadPolicy,
+}
+
+/// Holds a connection's place under the limit until dropped.
+#[derive(Debug)]
+pub(crate) struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max_connections: Option<usize>, policy: OverloadPolicy) -> Self {
+        Self {
+            permits: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            policy,
+        }
+    }
+
+    /// Admits a just accepted connection. When excess clients should wait
+    /// this waits for a free slot, which keeps the caller from accepting
+    /// any more meanwhile. `None` if it's over the limit and has to be
+    /// turned away.
+    pub async fn admit(&self) -> Option<Slot> {
+        let permits = match &self.permits {
+            Some(permits) => Arc::clone(permits),
+            None => return Some(Slot { _permit: None }),
+        };
+        let permit = match self.policy {
+            // The semaphore is never closed.
+            OverloadPolicy::Wait => permits.acquire_owned().await.ok(),
+            OverloadPolicy::Reject { .. } => permits.try_acquire_owned().ok(),
+        };
+        permit.map(|permit| Slot {
+            _permit: Some(permit),
+        })
+    }
+
+    /// How long rejected clients are asked to wait before retrying.
+    pub fn retry_after(&self) -> Duration {
+        match self.policy {
+            OverloadPolicy::Reject { retry_after } => retry_after,
+            OverloadPolicy::Wait => Duration::ZERO,
+        }
+    }
+}
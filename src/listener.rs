@@ -0,0 +1,183 @@
+use crate::stream::{ClientStream, PeerAddr};
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+/// Somewhere to accept connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    /// A TCP address such as `0.0.0.0:8080` or `[::]:8080`. Served over
+    /// HTTPS when TLS is enabled.
+    Tcp(String),
+    /// A Unix domain socket, always served as plain HTTP. `mode` sets the
+    /// socket's permissions, such as `0o660`, instead of leaving them to the
+    /// umask.
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+impl Bind {
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        Bind::Tcp(addr.into())
+    }
+
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Bind::Unix {
+            path: path.into(),
+            mode: None,
+        }
+    }
+}
+
+/// Parses `unix:/path/to/socket` as a Unix socket and anything else as a
+/// TCP address.
+impl FromStr for Bind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(()),
+            Some(path) => Ok(Bind::unix(path)),
+            None if s.is_empty() => Err(()),
+            None => Ok(Bind::tcp(s)),
+        }
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{}", addr),
+            Bind::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A bound `Bind`.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixSocket),
+}
+
+impl Listener {
+    pub async fn bind(bind: &Bind) -> io::Result<Self> {
+        match bind {
+            Bind::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            Bind::Unix { path, mode } => Ok(Listener::Unix(UnixSocket::bind(path, *mode).await?)),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(ClientStream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((stream.into(), PeerAddr::Tcp(addr)))
+            }
+            Listener::Unix(socket) => {
+                let (stream, _) = socket.listener.accept().await?;
+                Ok((stream.into(), PeerAddr::Unix))
+            }
+        }
+    }
+
+    /// The bound address of a TCP listener, with the port the OS picked
+    /// when bound to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            Listener::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(socket) => write!(f, "unix:{}", socket.path.display()),
+        }
+    }
+}
+
+/// A listening Unix socket. The socket file is removed again when it's
+/// dropped.
+#[derive(Debug)]
+pub(crate) struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    async fn bind(path: &Path, mode: Option<u32>) -> io::Result<Self> {
+        remove_stale_socket(path).await?;
+        let listener = match mode {
+            Some(mode) => bind_with_mode(path, mode)?,
+            None => UnixListener::bind(path)?,
+        };
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds in a private directory next to `path` and only moves the socket
+/// into place once it has `mode`, so clients never get to connect while it
+/// still has the umask's permissions.
+fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = parent.join(format!(".bind-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    // Only left over if binding failed part way.
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+/// Removes a socket file left behind by a server that didn't shut down
+/// cleanly. A socket still accepting connections belongs to a running
+/// server and is left alone, as is anything that isn't a socket.
+async fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", path.display()),
+        )),
+        Err(_) => fs::remove_file(path),
+    }
+}
@@ -39,6 +39,11 @@ impl Logger {
         Self { _private: () }
     }
 
+    /// Whether `ENV` is `development`, which is also the default.
+    pub fn is_development() -> bool {
+        *DEV_MODE
+    }
+
     fn format_status(status: u16) -> Option<String> {
         if !*DEV_MODE {
             return None;
@@ -66,6 +71,10 @@ impl Logger {
             HttpMethod::Put => (ColorCode::BG_YELLOW, "      "),
             HttpMethod::Patch => (ColorCode::BG_MAGENTA, "    "),
             HttpMethod::Delete => (ColorCode::BG_RED, "   "),
+            HttpMethod::Head => (ColorCode::BG_BLUE, "     "),
+            HttpMethod::Options => (ColorCode::BG_BLACK, "  "),
+            HttpMethod::Connect => (ColorCode::BG_BLACK, "  "),
+            HttpMethod::Trace => (ColorCode::BG_BLACK, "    "),
             HttpMethod::Unknown => (ColorCode::BG_BLACK, ""),
         };
 
@@ -96,10 +105,17 @@ impl Logger {
         let status_str =
             Self::format_status(request.status).unwrap_or_else(|| request.status.to_string());
 
+        let tls: String = [&request.server_name, &request.alpn_protocol]
+            .into_iter()
+            .flatten()
+            .map(|value| format!("{} | ", value))
+            .collect();
+
         println!(
-            "{} {} | {} | {} | {}ms",
+            "{} {} | {}{} | {} | {}ms",
             method_str,
             request.path,
+            tls,
             request.ip,
             status_str,
             request.duration.as_millis()
@@ -181,6 +197,10 @@ impl Display for HttpMethod {
             HttpMethod::Put => write!(f, "PUT"),
             HttpMethod::Patch => write!(f, "PATCH"),
             HttpMethod::Delete => write!(f, "DELETE"),
+            HttpMethod::Head => write!(f, "HEAD"),
+            HttpMethod::Options => write!(f, "OPTIONS"),
+            HttpMethod::Connect => write!(f, "CONNECT"),
+            HttpMethod::Trace => write!(f, "TRACE"),
             HttpMethod::Unknown => write!(f, "UNKNOWN"),
         }
     }
@@ -1,6 +1,10 @@
 use rust_tcp_srv::{
-    http::{Context, MiddlewareResult, ResponseBuilder},
+    http::{
+        BodyStream, Context, Event, EventStream, MiddlewareResult, ResponseBuilder, SseFuture,
+        StreamResponse,
+    },
     logger::LogLevel,
+    websocket::{WebSocket, WebSocketFuture},
     Config, Logger, Server,
 };
 use std::marker::PhantomData;
@@ -312,6 +316,46 @@ fn delete_handler(ctx: &Context) -> Vec<u8> {
         .build()
 }
 
+fn export_handler(_ctx: &Context) -> StreamResponse {
+    let header = std::iter::once(b"id,name\n".to_vec());
+    let rows = (1..=100_000).map(|id| format!("{},user-{}\n", id, id).into_bytes());
+    ResponseBuilder::ok()
+        .content_type("text/csv")
+        .stream(BodyStream::from_chunks(header.chain(rows)))
+}
+
+fn ticks_handler(events: EventStream) -> SseFuture {
+    Box::pin(async move {
+        // Carry on counting from wherever a reconnecting client left off.
+        let mut tick: u64 = events
+            .last_event_id()
+            .and_then(|id| id.parse().ok())
+            .unwrap_or(0);
+
+        loop {
+            tick += 1;
+            let event = Event::new()
+                .event("tick")
+                .id(tick.to_string())
+                .data(format!("{{\"tick\":{}}}", tick));
+            if events.send(event).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    })
+}
+
+fn echo_handler(mut ws: WebSocket) -> WebSocketFuture {
+    Box::pin(async move {
+        while let Some(message) = ws.recv().await {
+            if ws.send(message).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
 fn routes(server: &mut Server) {
     let mut api = server.router.group("/api");
 
@@ -331,6 +375,9 @@ fn routes(server: &mut Server) {
         .get("/api", root_handler)
         .get("/user/:id", user_handler)
         .get("/cookies", cookies_handler)
+        .get_stream("/export", export_handler)
+        .sse("/events/ticks", ticks_handler)
+        .websocket("/ws/echo", echo_handler)
         .post("/api", post_handler)
         .add_group(data)
         .add_group(user_group);
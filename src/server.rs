@@ -1,12 +1,28 @@
 use crate::{
+    acme::{CertificateManager, Challenges},
     config::Config,
-    connection::Connection,
-    http::{HttpHandler, MiddlewareHandler, RouteManager},
+    connection::{Connection, Routing},
+    http::{HttpHandler, MiddlewareHandler, ResponseBuilder, RouteManager},
+    limit::{ConnectionLimit, Slot},
+    listener::{Bind, Listener},
     logger::LogLevel,
+    shutdown::Shutdown,
+    stream::{ClientStream, PeerAddr},
+    tls::{self, ReloadableAcceptor, TlsConfig},
     Logger,
 };
-use std::{collections::HashMap, io, sync::Arc};
-use tokio::net::TcpListener;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
 
 pub struct Server {
     pub router: RouteManager,
@@ -16,6 +32,61 @@ pub struct Server {
     logger: Logger,
     http_handler: Option<Arc<HttpHandler>>,
     static_files: HashMap<String, &'static str>,
+    shutdown: Shutdown,
+}
+
+/// A server started with `Server::start`, serving in the background.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    shutdown: Shutdown,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// The address of the first TCP listener, with the port the OS picked
+    /// when configured with port 0. `None` when only serving Unix sockets.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().copied()
+    }
+
+    /// The addresses of every TCP listener, in the order they were
+    /// configured.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Stops accepting connections and lets the open ones drain. `join`
+    /// resolves once that's done.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// The number of connections being served right now.
+    pub fn active_connections(&self) -> usize {
+        self.shutdown.active_connections()
+    }
+
+    /// Waits for the server to stop, returning why it did if that wasn't a
+    /// shutdown.
+    pub async fn join(self) -> io::Result<()> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+/// What every listener's accept loop shares.
+#[derive(Clone)]
+struct Serving {
+    handler: Arc<HttpHandler>,
+    config: Arc<Config>,
+    limit: ConnectionLimit,
+    shutdown: Shutdown,
+    logger: Logger,
+    /// Pending ACME challenges, answered on the plain HTTP listeners.
+    challenges: Challenges,
 }
 
 impl Server {
@@ -27,14 +98,36 @@ impl Server {
             http_handler: None,
             middleware: MiddlewareHandler::new(),
             static_files: HashMap::new(),
+            shutdown: Shutdown::new(),
         }
     }
 
+    /// A trigger that stops the server from outside, the same way SIGTERM
+    /// does.
+    pub fn shutdown_trigger(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// The number of connections being served right now.
+    pub fn active_connections(&self) -> usize {
+        self.shutdown.active_connections()
+    }
+
     pub fn static_file(&mut self, route: &str, file_path: &'static str) {
         self.static_files.insert(route.to_string(), file_path);
     }
 
+    /// Serves until shutdown is triggered, by SIGTERM, SIGINT or a
+    /// `shutdown_trigger`, and open connections have drained.
     pub async fn run(&mut self) -> io::Result<()> {
+        self.shutdown.watch_signals(self.logger.clone())?;
+        self.start().await?.join().await
+    }
+
+    /// Binds the listeners and serves in the background, returning as soon
+    /// as connections are being accepted. Unlike `run` it leaves signals to
+    /// the application, the server is stopped through the returned handle.
+    pub async fn start(&mut self) -> io::Result<ServerHandle> {
         if self.router.routes().len() == 0 {
             self.logger.log(
                 LogLevel::Application,
@@ -49,24 +142,522 @@ impl Server {
         self.http_handler = Some(Arc::new(HttpHandler::new(
             shared_router,
             shared_middleware,
-            static_files
+            static_files,
         )));
 
-        let addr = format!("{}:{}", self.config.host, self.config.port);
-        let listener = TcpListener::bind(&addr).await?;
+        // Bound before anything else, so a configured port 0 is resolved to
+        // the real one before redirects refer to it.
+        let binds = match self.config.binds.is_empty() {
+            true => vec![Bind::tcp(format!(
+                "{}:{}",
+                self.config.host, self.config.port
+            ))],
+            false => self.config.binds.clone(),
+        };
+        let mut listeners = Vec::with_capacity(binds.len());
+        for bind in &binds {
+            let listener = Listener::bind(bind).await.map_err(|e| {
+                self.logger
+                    .log(LogLevel::Error, &format!("Failed to bind {}: {}", bind, e));
+                e
+            })?;
+            listeners.push(listener);
+        }
+        let local_addrs: Vec<SocketAddr> =
+            listeners.iter().filter_map(Listener::local_addr).collect();
+        let config = Config {
+            port: local_addrs
+                .first()
+                .map_or(self.config.port, |addr| addr.port()),
+            ..self.config.clone()
+        };
+
+        let serving = Serving {
+            handler: Arc::clone(self.http_handler.as_ref().unwrap()),
+            config: Arc::new(config),
+            limit: ConnectionLimit::new(self.config.max_connections, self.config.overload_policy),
+            shutdown: self.shutdown.clone(),
+            logger: self.logger.clone(),
+            challenges: Challenges::default(),
+        };
+
+        let redirect_port = self.config.https_redirect.and_then(|r| r.port);
+        // Plain HTTP listeners for challenges and redirects, stopped again
+        // if a later step fails.
+        let mut plain_loops = Vec::new();
+
+        let certificates = match &self.config.acme {
+            Some(acme) => {
+                // The CA validates over plain HTTP, so challenges need their
+                // own listeners before anything can be issued. When it's also
+                // the redirect port, everything else is redirected, otherwise
+                // it's not found.
+                let routing = match redirect_port == Some(acme.http_port) {
+                    true => Routing::RedirectToHttps,
+                    false => Routing::AcmeChallenges,
+                };
+                plain_loops.extend(
+                    self.start_plain(
+                        acme.http_port,
+                        &local_addrs,
+                        routing,
+                        &serving,
+                        "Answering ACME challenges",
+                    )
+                    .await?,
+                );
+
+                let manager = Arc::new(CertificateManager::new(
+                    acme.clone(),
+                    serving.challenges.clone(),
+                ));
+                if let Err(e) = manager.ensure_certificate().await {
+                    self.logger.log(
+                        LogLevel::Error,
+                        &format!("Certificate request failed: {}", e),
+                    );
+                    // An older certificate can still be served while renewal
+                    // is retried.
+                    if !acme.cert_path().exists() {
+                        return Err(Self::abort_start(&serving, plain_loops, e).await);
+                    }
+                }
+                Some(manager)
+            }
+            None => None,
+        };
+
+        let acceptor = match self.tls_acceptor() {
+            Ok(acceptor) => acceptor,
+            Err(e) => {
+                self.logger
+                    .log(LogLevel::Error, &format!("Failed to configure TLS: {}", e));
+                return Err(Self::abort_start(&serving, plain_loops, e).await);
+            }
+        };
+        if let (Some(manager), Some(acceptor)) = (certificates, &acceptor) {
+            manager.spawn_renewal(Arc::clone(acceptor), self.shutdown.clone());
+        }
+
+        let acme_port = self.config.acme.as_ref().map(|acme| acme.http_port);
+        if let Some(port) =
+            redirect_port.filter(|port| acceptor.is_some() && acme_port != Some(*port))
+        {
+            match self
+                .start_plain(
+                    port,
+                    &local_addrs,
+                    Routing::RedirectToHttps,
+                    &serving,
+                    "Redirecting HTTP to HTTPS",
+                )
+                .await
+            {
+                Ok(started) => plain_loops.extend(started),
+                Err(e) => return Err(Self::abort_start(&serving, plain_loops, e).await),
+            }
+        }
+
+        let mut accept_loops = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            self.logger.log(
+                LogLevel::Info,
+                &format!("Server is listening on {}", listener),
+            );
+            // Unix sockets only take local clients, so they skip TLS.
+            let acceptor = match listener {
+                Listener::Tcp(_) => acceptor.clone(),
+                Listener::Unix(_) => None,
+            };
+            let serving = serving.clone();
+            accept_loops.push(tokio::spawn(async move {
+                let result =
+                    Self::accept_loop(listener, acceptor, Routing::App, serving.clone()).await;
+                // A listener failing takes the others down with it.
+                if result.is_err() {
+                    serving.shutdown.trigger();
+                }
+                result
+            }));
+        }
+
+        let task = tokio::spawn(async move {
+            let mut result = Ok(());
+            for accept_loop in accept_loops.into_iter().chain(plain_loops) {
+                let stopped = accept_loop
+                    .await
+                    .unwrap_or_else(|e| Err(io::Error::other(e)));
+                result = result.and(stopped);
+            }
+            Self::drain(&serving).await;
+            result
+        });
+        Ok(ServerHandle {
+            local_addrs,
+            shutdown: self.shutdown.clone(),
+            task,
+        })
+    }
+
+    /// Binds a plain HTTP listener on `port` for each host the TCP binds
+    /// use and starts accepting on them. Nothing is started unless all of
+    /// them could be bound.
+    async fn start_plain(
+        &self,
+        port: u16,
+        local_addrs: &[SocketAddr],
+        routing: Routing,
+        serving: &Serving,
+        purpose: &str,
+    ) -> io::Result<Vec<JoinHandle<io::Result<()>>>> {
+        let mut listeners = Vec::new();
+        for addr in plain_addrs(&self.config.host, local_addrs, port) {
+            let listener = TcpListener::bind(&addr).await.map_err(|e| {
+                self.logger
+                    .log(LogLevel::Error, &format!("Failed to bind {}: {}", addr, e));
+                e
+            })?;
+            listeners.push((addr, listener));
+        }
+        let mut started = Vec::with_capacity(listeners.len());
+        for (addr, listener) in listeners {
+            self.logger
+                .log(LogLevel::Info, &format!("{} on {}", purpose, addr));
+            started.push(tokio::spawn(Self::accept_loop(
+                Listener::Tcp(listener),
+                None,
+                routing,
+                serving.clone(),
+            )));
+        }
+        Ok(started)
+    }
+
+    /// Stops the listeners already started when `start` fails part way, so
+    /// none of them keeps accepting behind the returned error.
+    async fn abort_start(
+        serving: &Serving,
+        started: Vec<JoinHandle<io::Result<()>>>,
+        error: io::Error,
+    ) -> io::Error {
+        serving.shutdown.trigger();
+        for accept_loop in started {
+            let _ = accept_loop.await;
+        }
+        error
+    }
+
+    /// Gives open connections up to the grace period to finish, then closes
+    /// whatever is left.
+    async fn drain(serving: &Serving) {
+        let grace_period = serving.config.shutdown_grace_period;
+        let active = serving.shutdown.active_connections();
+        if active > 0 {
+            serving.logger.log(
+                LogLevel::Info,
+                &format!(
+                    "Waiting up to {}s for {} open connections",
+                    grace_period.as_secs(),
+                    active
+                ),
+            );
+        }
+        if timeout(grace_period, serving.shutdown.drained())
+            .await
+            .is_err()
+        {
+            serving.logger.log(
+                LogLevel::Warning,
+                &format!(
+                    "Grace period over, closing {} connections",
+                    serving.shutdown.active_connections()
+                ),
+            );
+        }
+        serving.shutdown.close();
+        serving.logger.log(LogLevel::Info, "Server stopped");
+    }
+
+    /// Loads the certificates to serve with, or `None` without TLS.
+    fn tls_acceptor(&self) -> io::Result<Option<Arc<ReloadableAcceptor>>> {
+        let tls_config = match self.tls_config()? {
+            Some(tls_config) => tls_config,
+            None => return Ok(None),
+        };
+
+        let acceptor = Arc::new(ReloadableAcceptor::new(tls_config.clone())?);
         self.logger.log(
             LogLevel::Info,
-            &format!("Server is listening on Port: {}", self.config.port),
+            &format!("TLS enabled with {}", tls_config.cert_path.display()),
         );
+        for cert in &tls_config.certificates {
+            self.logger.log(
+                LogLevel::Info,
+                &format!("Serving {} for {}", cert.cert_path.display(), cert.hostname),
+            );
+        }
+        Arc::clone(&acceptor).watch(self.shutdown.clone())?;
+        Ok(Some(acceptor))
+    }
+
+    /// The TLS settings to serve with. Certificates issued through ACME take
+    /// the place of any configured default certificate, and development
+    /// servers without one get a self-signed certificate.
+    fn tls_config(&self) -> io::Result<Option<TlsConfig>> {
+        let mut tls = match (&self.config.tls, &self.config.acme) {
+            (Some(tls), _) => tls.clone(),
+            (None, Some(acme)) => TlsConfig::new(acme.cert_path(), acme.key_path()),
+            (None, None) => return Ok(None),
+        };
+        if let Some(acme) = &self.config.acme {
+            tls.cert_path = acme.cert_path();
+            tls.key_path = acme.key_path();
+        }
+
+        if !tls.has_certificate() {
+            if !Logger::is_development() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "TLS is enabled without a certificate, configure one or set ENV=development",
+                ));
+            }
+            (tls.cert_path, tls.key_path) = tls::dev_certificate(&tls.dev_cert_dir)?;
+        }
+        Ok(Some(tls))
+    }
 
+    /// Serves connections from `listener`, over TLS when there is an
+    /// `acceptor`, handling requests as `routing` says.
+    /// Returns once shutdown is triggered, leaving open connections to
+    /// drain.
+    async fn accept_loop(
+        listener: Listener,
+        acceptor: Option<Arc<ReloadableAcceptor>>,
+        routing: Routing,
+        serving: Serving,
+    ) -> io::Result<()> {
         loop {
-            let (socket, _addr) = listener.accept().await?;
-            let handler = Arc::clone(self.http_handler.as_ref().unwrap());
-            tokio::spawn(async move {
-                if let Err(e) = Connection::new(socket, handler).unwrap().process().await {
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = serving.shutdown.triggered() => return Ok(()),
+            };
+            // Waiting for a slot here leaves further clients in the listen
+            // backlog without holding one while nobody is connecting.
+            let slot = tokio::select! {
+                slot = serving.limit.admit() => slot,
+                _ = serving.shutdown.triggered() => return Ok(()),
+            };
+            tokio::spawn(Self::serve_connection(
+                socket,
+                addr,
+                acceptor.clone(),
+                routing,
+                slot,
+                serving.clone(),
+            ));
+        }
+    }
+
+    /// Serves one accepted connection. Without a `slot` it's over the
+    /// connection limit and only gets a 503.
+    async fn serve_connection(
+        socket: ClientStream,
+        addr: PeerAddr,
+        acceptor: Option<Arc<ReloadableAcceptor>>,
+        routing: Routing,
+        slot: Option<Slot>,
+        serving: Serving,
+    ) {
+        let Serving {
+            handler,
+            config,
+            shutdown,
+            logger,
+            challenges,
+            ..
+        } = serving;
+
+        // Clients get as long to finish the handshake as they get to send
+        // their headers.
+        let handshake_timeout = config.header_read_timeout;
+        let (mut stream, routing, virtual_host) = match (acceptor, socket) {
+            (Some(acceptor), ClientStream::Tcp(socket)) => {
+                match timeout(handshake_timeout, first_byte(&socket)).await {
+                    // A TLS handshake record.
+                    Ok(Some(0x16)) => {
+                        let (tls_acceptor, resolver) = acceptor.current();
+                        match timeout(handshake_timeout, tls_acceptor.accept(socket)).await {
+                            Ok(Ok(stream)) => {
+                                let (hostname, _) =
+                                    resolver.lookup(stream.get_ref().1.server_name());
+                                (ClientStream::from(stream), routing, hostname)
+                            }
+                            Ok(Err(e)) => {
+                                logger.log(
+                                    LogLevel::Warning,
+                                    &format!("TLS handshake with {} failed: {}", addr, e),
+                                );
+                                return;
+                            }
+                            Err(_) => {
+                                logger.log(
+                                    LogLevel::Warning,
+                                    &format!("TLS handshake with {} timed out", addr),
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    Ok(Some(_)) if config.https_redirect.is_some() => {
+                        (ClientStream::from(socket), Routing::RedirectToHttps, None)
+                    }
+                    Ok(Some(_)) => {
+                        logger.log(
+                            LogLevel::Warning,
+                            &format!("Plain HTTP from {} on the HTTPS port", addr),
+                        );
+                        return;
+                    }
+                    Ok(None) => return,
+                    Err(_) => {
+                        logger.log(
+                            LogLevel::Warning,
+                            &format!("Timed out waiting for a request from {}", addr),
+                        );
+                        return;
+                    }
+                }
+            }
+            (_, stream) => (stream, routing, None),
+        };
+
+        let _slot = match slot {
+            Some(slot) => slot,
+            None => {
+                logger.log(
+                    LogLevel::Warning,
+                    &format!(
+                        "Turned away {} with {} connections open",
+                        addr,
+                        shutdown.active_connections()
+                    ),
+                );
+                let response = ResponseBuilder::new()
+                    .status(ResponseBuilder::SERVICE_UNAVAILABLE)
+                    .header(
+                        "Retry-After",
+                        &serving.limit.retry_after().as_secs().to_string(),
+                    )
+                    .header("Connection", "close")
+                    .text("Service Unavailable")
+                    .build();
+                let reject = async {
+                    stream.write_all(&response).await?;
+                    stream.shutdown().await
+                };
+                let _ = timeout(config.write_timeout, reject).await;
+                return;
+            }
+        };
+        let _guard = shutdown.track();
+
+        let connection = Connection::new(stream, handler, config)
+            .unwrap()
+            .with_shutdown(shutdown.clone())
+            .virtual_host(virtual_host)
+            .routing(routing)
+            .acme_challenges(challenges);
+        tokio::select! {
+            result = connection.process() => {
+                if let Err(e) = result {
                     eprintln!("Connection error: {}", e);
                 }
-            });
+            }
+            // Still busy when the grace period ran out.
+            _ = shutdown.closed() => {}
         }
     }
 }
+
+/// The first byte the client sent, without consuming it. `None` if it closed
+/// the connection first.
+async fn first_byte(socket: &TcpStream) -> Option<u8> {
+    let mut byte = [0u8];
+    match socket.peek(&mut byte).await {
+        Ok(1) => Some(byte[0]),
+        _ => None,
+    }
+}
+
+/// Addresses for plain HTTP listeners on `port`: the hosts the TCP binds
+/// listen on, or `host` when there are none. A wildcard host already covers
+/// the specific ones.
+fn plain_addrs(host: &str, local_addrs: &[SocketAddr], port: u16) -> Vec<String> {
+    let mut hosts: Vec<IpAddr> = local_addrs.iter().map(SocketAddr::ip).collect();
+    if hosts.iter().any(IpAddr::is_unspecified) {
+        hosts.retain(IpAddr::is_unspecified);
+    }
+    hosts.sort();
+    hosts.dedup();
+    match hosts.is_empty() {
+        true => vec![format!("{}:{}", host, port)],
+        false => hosts
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port).to_string())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acme::AcmeConfig;
+
+    async fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn failed_start_stops_the_challenge_listener() {
+        let http_port = free_port().await;
+        let storage = std::env::temp_dir().join(format!("acme-start-{}", std::process::id()));
+        // Issuing fails without a CA bundle, and there's no older certificate.
+        let acme = AcmeConfig::new(["example.test"])
+            .storage_dir(&storage)
+            .ca_bundle(storage.join("missing.pem"))
+            .http_port(http_port);
+        let mut server = Server::new(Config {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            acme: Some(acme),
+            ..Config::default()
+        });
+
+        assert!(server.start().await.is_err());
+        assert!(TcpListener::bind(("127.0.0.1", http_port)).await.is_ok());
+        let _ = std::fs::remove_dir_all(storage);
+    }
+
+    #[test]
+    fn plain_listeners_follow_the_tcp_binds() {
+        let addrs = |list: &[&str]| -> Vec<SocketAddr> {
+            list.iter().map(|addr| addr.parse().unwrap()).collect()
+        };
+
+        assert_eq!(
+            plain_addrs(
+                "0.0.0.0",
+                &addrs(&["10.0.0.1:443", "[::1]:443", "10.0.0.1:8443"]),
+                80
+            ),
+            ["10.0.0.1:80", "[::1]:80"]
+        );
+        assert_eq!(
+            plain_addrs("0.0.0.0", &addrs(&["127.0.0.1:443", "[::]:443"]), 80),
+            ["[::]:80"]
+        );
+        // Only Unix sockets.
+        assert_eq!(plain_addrs("127.0.0.1", &[], 80), ["127.0.0.1:80"]);
+    }
+}
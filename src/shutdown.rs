@@ -0,0 +1,129 @@
+use crate::logger::{LogLevel, Logger};
+use std::{io, sync::Arc};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    Running,
+    /// No new connections are accepted, open ones finish what they are doing.
+    Draining,
+    /// The grace period is over, whatever is left gets cut off.
+    Closed,
+}
+
+/// Stops a running server. Cloning it gives another trigger for the same
+/// server, so it can be handed to whatever decides when to stop.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    state: Arc<watch::Sender<State>>,
+    connections: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(watch::Sender::new(State::Running)),
+            connections: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    /// Stops accepting connections and lets the open ones drain. Does
+    /// nothing if shutdown has already begun.
+    pub fn trigger(&self) {
+        self.advance(State::Draining);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.state.borrow() >= State::Draining
+    }
+
+    /// The number of connections currently open.
+    pub fn active_connections(&self) -> usize {
+        *self.connections.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn triggered(&self) {
+        self.reached(State::Draining).await
+    }
+
+    /// Resolves once the grace period is over and remaining connections
+    /// should be closed.
+    pub(crate) async fn closed(&self) {
+        self.reached(State::Closed).await
+    }
+
+    pub(crate) fn close(&self) {
+        self.advance(State::Closed);
+    }
+
+    /// Resolves once every tracked connection has finished.
+    pub(crate) async fn drained(&self) {
+        let mut connections = self.connections.subscribe();
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = connections.wait_for(|&count| count == 0).await;
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(crate) fn track(&self) -> ConnectionGuard {
+        self.connections.send_modify(|count| *count += 1);
+        ConnectionGuard {
+            connections: Arc::clone(&self.connections),
+        }
+    }
+
+    /// Triggers shutdown on SIGTERM or SIGINT, until shutdown has begun
+    /// some other way.
+    pub(crate) fn watch_signals(&self, logger: Logger) -> io::Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let shutdown = self.clone();
+
+        tokio::spawn(async move {
+            let name = tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+                _ = shutdown.triggered() => return,
+            };
+            logger.log(LogLevel::Info, &format!("{} received, shutting down", name));
+            shutdown.trigger();
+        });
+        Ok(())
+    }
+
+    fn advance(&self, state: State) {
+        self.state.send_if_modified(|current| {
+            let advanced = *current < state;
+            if advanced {
+                *current = state;
+            }
+            advanced
+        });
+    }
+
+    async fn reached(&self, state: State) {
+        let mut current = self.state.subscribe();
+        let _ = current.wait_for(|&current| current >= state).await;
+    }
+}
+
+/// Keeps a connection counted in `Shutdown::active_connections`.
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    connections: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.send_modify(|count| *count -= 1);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixStream},
+};
+use tokio_rustls::server::TlsStream;
+
+use crate::tls::{PeerCertificate, TlsInfo};
+
+/// A client connection, either plain TCP, TLS over TCP or a Unix domain
+/// socket.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+/// Where a client connected from. Clients on a Unix socket are local and
+/// usually unnamed, so they're only told apart from TCP ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+impl ClientStream {
+    pub fn peer_addr(&self) -> io::Result<PeerAddr> {
+        match self {
+            ClientStream::Tcp(stream) => stream.peer_addr().map(PeerAddr::Tcp),
+            ClientStream::Tls(stream) => stream.get_ref().0.peer_addr().map(PeerAddr::Tcp),
+            ClientStream::Unix(_) => Ok(PeerAddr::Unix),
+        }
+    }
+
+    /// Details of the TLS session, or `None` for plain connections.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self {
+            ClientStream::Tcp(_) | ClientStream::Unix(_) => None,
+            ClientStream::Tls(stream) => {
+                let session = stream.get_ref().1;
+                Some(TlsInfo {
+                    // Filled in from the certificate resolver by the server.
+                    server_name: None,
+                    sni: session.server_name().map(str::to_string),
+                    alpn_protocol: session
+                        .alpn_protocol()
+                        .map(|p| String::from_utf8_lossy(p).into_owned()),
+                    peer_certificate: session
+                        .peer_certificates()
+                        .and_then(|chain| chain.first())
+                        .and_then(|cert| PeerCertificate::from_der(cert)),
+                })
+            }
+        }
+    }
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        ClientStream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for ClientStream {
+    fn from(stream: UnixStream) -> Self {
+        ClientStream::Unix(stream)
+    }
+}
+
+impl From<TlsStream<TcpStream>> for ClientStream {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        ClientStream::Tls(Box::new(stream))
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
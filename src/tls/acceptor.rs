@@ -0,0 +1,133 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore, ServerConfig, SupportedProtocolVersion,
+};
+use tokio_rustls::TlsAcceptor;
+
+use super::{ClientAuth, SniResolver, TlsConfig, TlsVersion, ALPN_H2, ALPN_HTTP11};
+
+/// Loads the certificates named in `config` and builds the acceptor that
+/// wraps incoming connections.
+pub fn build_acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    build(config).map(|(acceptor, _)| acceptor)
+}
+
+/// Builds the acceptor along with the resolver picking its certificates.
+pub(crate) fn build(config: &TlsConfig) -> io::Result<(TlsAcceptor, Arc<SniResolver>)> {
+    let provider = Arc::new(provider(config)?);
+    let resolver = Arc::new(SniResolver::new(config, &provider)?);
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(versions(config.min_version))
+        .map_err(invalid)?;
+
+    let builder = match &config.client_auth {
+        Some(client_auth) => {
+            builder.with_client_cert_verifier(client_verifier(client_auth, provider)?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(Arc::clone(&resolver) as _);
+    // In order of preference. Handshakes offering only other protocols are
+    // refused, while clients offering none are sniffed once connected.
+    server_config.alpn_protocols = vec![ALPN_H2.into(), ALPN_HTTP11.into()];
+
+    Ok((TlsAcceptor::from(Arc::new(server_config)), resolver))
+}
+
+/// The ring provider, limited to the configured cipher suites.
+fn provider(config: &TlsConfig) -> io::Result<CryptoProvider> {
+    let mut provider = ring::default_provider();
+    if config.cipher_suites.is_empty() {
+        return Ok(provider);
+    }
+
+    let mut suites = Vec::new();
+    for name in &config.cipher_suites {
+        let suite = ring::ALL_CIPHER_SUITES
+            .iter()
+            .find(|s| format!("{:?}", s.suite()).eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| invalid(format!("Unknown cipher suite: {}", name)))?;
+        suites.push(*suite);
+    }
+    provider.cipher_suites = suites;
+    Ok(provider)
+}
+
+/// Verifies client certificates against the CA bundle in `client_auth`.
+fn client_verifier(
+    client_auth: &ClientAuth,
+    provider: Arc<CryptoProvider>,
+) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&client_auth.ca_path)? {
+        roots
+            .add(cert)
+            .map_err(|e| invalid(format!("{}: {}", client_auth.ca_path.display(), e)))?;
+    }
+
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let builder = match client_auth.required {
+        true => builder,
+        false => builder.allow_unauthenticated(),
+    };
+    builder.build().map_err(invalid)
+}
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+fn versions(min_version: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match min_version {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
+}
+
+pub fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "No certificates found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid(format!("No private key found in {}", path.display())))
+}
+
+/// Replaces `path` atomically, readable only by the owner.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+pub(super) fn invalid(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+}
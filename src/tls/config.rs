@@ -0,0 +1,156 @@
+use std::{path::PathBuf, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl std::str::FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().trim_start_matches("tls").trim() {
+            "1.2" | "12" => Ok(TlsVersion::Tls12),
+            "1.3" | "13" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("Unsupported TLS version: {}", s)),
+        }
+    }
+}
+
+/// A certificate served to clients asking for `hostname` through SNI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniCertificate {
+    /// An exact name such as `api.example.com`, or a wildcard such as
+    /// `*.example.com` covering one extra label.
+    pub hostname: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Client certificate authentication against a CA bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAuth {
+    /// PEM file holding the CA certificates client certificates must chain
+    /// to.
+    pub ca_path: PathBuf,
+    /// Refuse handshakes without a client certificate. Otherwise one is
+    /// requested, and only verified if the client sends it.
+    pub required: bool,
+}
+
+/// Certificate and handshake policy for serving HTTPS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// PEM file holding the certificate chain, leaf first. It is the
+    /// default, served when no entry in `certificates` matches the SNI name.
+    pub cert_path: PathBuf,
+    /// PEM file holding the private key (PKCS#8, PKCS#1 or SEC1).
+    pub key_path: PathBuf,
+    /// Oldest protocol version clients may negotiate.
+    pub min_version: TlsVersion,
+    /// Cipher suites to offer, by their IANA names such as
+    /// `TLS13_AES_256_GCM_SHA384`. Empty means the rustls defaults.
+    pub cipher_suites: Vec<String>,
+    /// Extra certificates picked by SNI hostname. Each may use its own key
+    /// type.
+    pub certificates: Vec<SniCertificate>,
+    /// Ask clients for a certificate, or `None` to never ask.
+    pub client_auth: Option<ClientAuth>,
+    /// How often to check the files above for changes and reload them.
+    /// `None` only reloads on SIGHUP.
+    pub reload_interval: Option<Duration>,
+    /// Where a self-signed certificate is kept when TLS is enabled without
+    /// one in development.
+    pub dev_cert_dir: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            min_version: TlsVersion::Tls12,
+            cipher_suites: Vec::new(),
+            certificates: Vec::new(),
+            client_auth: None,
+            reload_interval: Some(Duration::from_secs(30)),
+            dev_cert_dir: PathBuf::from(".dev-certs"),
+        }
+    }
+
+    /// TLS without a configured certificate. With `ENV=development` a
+    /// self-signed one for localhost is generated on startup; anywhere else
+    /// the server refuses to start.
+    pub fn development() -> Self {
+        Self::new("", "")
+    }
+
+    /// Whether a certificate was configured.
+    pub fn has_certificate(&self) -> bool {
+        !self.cert_path.as_os_str().is_empty()
+    }
+
+    pub fn dev_cert_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dev_cert_dir = dir.into();
+        self
+    }
+
+    /// Serves `cert_path` to clients asking for `hostname`.
+    pub fn certificate(
+        mut self,
+        hostname: impl Into<String>,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Self {
+        self.certificates.push(SniCertificate {
+            hostname: hostname.into(),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+
+    /// Refuses clients that don't present a certificate issued by a CA in
+    /// `ca_path`.
+    pub fn require_client_cert(mut self, ca_path: impl Into<PathBuf>) -> Self {
+        self.client_auth = Some(ClientAuth {
+            ca_path: ca_path.into(),
+            required: true,
+        });
+        self
+    }
+
+    /// Asks clients for a certificate issued by a CA in `ca_path`, but lets
+    /// them connect without one.
+    pub fn request_client_cert(mut self, ca_path: impl Into<PathBuf>) -> Self {
+        self.client_auth = Some(ClientAuth {
+            ca_path: ca_path.into(),
+            required: false,
+        });
+        self
+    }
+
+    pub fn reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    pub(crate) fn default_certificate(&self) -> SniCertificate {
+        SniCertificate {
+            hostname: String::new(),
+            cert_path: self.cert_path.clone(),
+            key_path: self.key_path.clone(),
+        }
+    }
+
+    pub fn min_version(mut self, version: TlsVersion) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    pub fn cipher_suites<S: Into<String>>(mut self, suites: impl IntoIterator<Item = S>) -> Self {
+        self.cipher_suites = suites.into_iter().map(Into::into).collect();
+        self
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+
+use super::{acceptor::invalid, load_certs, write_private, PeerCertificate};
+use crate::logger::{LogLevel, Logger};
+
+/// Names the development certificate is valid for.
+const DEV_NAMES: [&str; 2] = ["localhost", "127.0.0.1"];
+
+/// Returns the certificate and key paths of a self-signed certificate for
+/// local development, generating them under `dir` the first time.
+pub fn dev_certificate(dir: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    let logger = Logger::new();
+    let cert_path = dir.join("localhost.pem");
+    let key_path = dir.join("localhost-key.pem");
+
+    if !(cert_path.exists() && key_path.exists()) {
+        fs::create_dir_all(dir)?;
+        let key = KeyPair::generate().map_err(invalid)?;
+        let mut params = CertificateParams::new(DEV_NAMES.map(String::from)).map_err(invalid)?;
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, "localhost development certificate");
+        params.distinguished_name = name;
+        let cert = params.self_signed(&key).map_err(invalid)?;
+
+        write_private(&key_path, key.serialize_pem().as_bytes())?;
+        write_private(&cert_path, cert.pem().as_bytes())?;
+        logger.log(
+            LogLevel::Info,
+            &format!("Generated a development certificate in {}", dir.display()),
+        );
+    }
+
+    let fingerprint = load_certs(&cert_path)?
+        .first()
+        .and_then(|cert| PeerCertificate::from_der(cert))
+        .map(|cert| cert.fingerprint)
+        .ok_or_else(|| invalid(format!("Unreadable certificate in {}", cert_path.display())))?;
+    logger.log(
+        LogLevel::Info,
+        &format!(
+            "Using self-signed certificate {} for {} (SHA-256 fingerprint {})",
+            cert_path.display(),
+            DEV_NAMES.join(", "),
+            fingerprint
+        ),
+    );
+
+    Ok((cert_path, key_path))
+}
@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+
+use sha2::{Digest, Sha256};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// ALPN identifier for HTTP/2 over TLS.
+pub const ALPN_H2: &str = "h2";
+/// ALPN identifier for HTTP/1.1.
+pub const ALPN_HTTP11: &str = "http/1.1";
+
+/// What a request's connection negotiated during the TLS handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The configured hostname whose certificate answered, such as
+    /// `api.example.com` or `*.example.com`, naming the virtual host. `None`
+    /// when the default certificate answered.
+    pub server_name: Option<String>,
+    /// The hostname the client asked for through SNI, as sent. `None` when
+    /// the client sent none.
+    pub sni: Option<String>,
+    /// The application protocol agreed through ALPN, such as `h2`. `None`
+    /// when the client offered none.
+    pub alpn_protocol: Option<String>,
+    /// The client certificate, once verified against the configured CA.
+    pub peer_certificate: Option<PeerCertificate>,
+}
+
+/// Identity details of a verified client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    /// The distinguished name, such as `CN=billing, O=Example`.
+    pub subject: String,
+    /// DNS names, email addresses, URIs and IP addresses from the subject
+    /// alternative name extension.
+    pub sans: Vec<String>,
+    /// Lowercase hex SHA-256 of the DER encoded certificate.
+    pub fingerprint: String,
+}
+
+impl PeerCertificate {
+    /// Reads the details out of a DER encoded certificate.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(extension)) => extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    GeneralName::IPAddress(bytes) => ip_address(bytes),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            sans,
+            fingerprint: Sha256::digest(der)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        })
+    }
+}
+
+fn ip_address(bytes: &[u8]) -> Option<String> {
+    let ip = match bytes.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?),
+        _ => return None,
+    };
+    Some(ip.to_string())
+}
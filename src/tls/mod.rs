@@ -0,0 +1,15 @@
+mod acceptor;
+mod config;
+mod dev_cert;
+mod info;
+mod redirect;
+mod reload;
+mod resolver;
+
+pub use acceptor::{build_acceptor, load_certs, load_key, write_private};
+pub use config::{ClientAuth, SniCertificate, TlsConfig, TlsVersion};
+pub use dev_cert::dev_certificate;
+pub use info::{PeerCertificate, TlsInfo, ALPN_H2, ALPN_HTTP11};
+pub use redirect::{Hsts, HttpsRedirect};
+pub use reload::ReloadableAcceptor;
+pub use resolver::SniResolver;
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use crate::http::{HttpRequest, Res, ResponseBuilder};
+
+/// Sends plain HTTP clients to the HTTPS origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpsRedirect {
+    /// A plain HTTP port, usually 80, whose requests are all redirected.
+    /// It's listened on at each host the TCP binds use. Plain requests sent
+    /// to the HTTPS port are redirected either way.
+    pub port: Option<u16>,
+    /// `PERMANENT_REDIRECT` keeps the method and body, `MOVED_PERMANENTLY`
+    /// suits older clients.
+    pub status: (u16, &'static str),
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> Self {
+        Self {
+            port: Some(80),
+            status: ResponseBuilder::PERMANENT_REDIRECT,
+        }
+    }
+}
+
+impl HttpsRedirect {
+    /// The redirect for `request` to the same host and path on `https_port`.
+    /// `default_host` is used when the request has no `Host` header.
+    pub fn response(&self, request: &HttpRequest, https_port: u16, default_host: &str) -> Res {
+        let host = request
+            .headers
+            .get("host")
+            .map(|host| strip_port(host))
+            .unwrap_or(default_host);
+        let location = match https_port {
+            443 => format!("https://{}{}", host, request.path),
+            port => format!("https://{}:{}{}", host, port, request.path),
+        };
+
+        let response = ResponseBuilder::new()
+            .status(self.status)
+            .header("Location", &location)
+            .body(Vec::new())
+            .build();
+        Res::new(response, self.status.0)
+    }
+}
+
+/// Strips the port from a `Host` header, leaving IPv6 literals bracketed.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host,
+    }
+}
+
+/// The `Strict-Transport-Security` policy sent on HTTPS responses. Browsers
+/// ignore it over plain HTTP, so redirects don't carry it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hsts {
+    pub max_age: Duration,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl Default for Hsts {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(365 * 24 * 60 * 60),
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+impl Hsts {
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    logger::{LogLevel, Logger},
+    shutdown::Shutdown,
+};
+
+use super::{acceptor::build, SniResolver, TlsConfig};
+
+/// Modification time and length of each watched file, in a fixed order.
+type Snapshot = Vec<Option<(SystemTime, u64)>>;
+
+/// A `TlsAcceptor` whose certificates can be swapped while the server runs.
+///
+/// Each reload builds a complete new acceptor before swapping it in, so
+/// handshakes never see a half loaded state. Connections that already
+/// finished their handshake keep the configuration they started with.
+pub struct ReloadableAcceptor {
+    config: TlsConfig,
+    current: RwLock<(TlsAcceptor, Arc<SniResolver>)>,
+    logger: Logger,
+}
+
+impl ReloadableAcceptor {
+    pub fn new(config: TlsConfig) -> io::Result<Self> {
+        let current = build(&config)?;
+        Ok(Self {
+            config,
+            current: RwLock::new(current),
+            logger: Logger::new(),
+        })
+    }
+
+    /// The acceptor to use for the next handshake.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.current().0
+    }
+
+    /// The acceptor for the next handshake together with the resolver its
+    /// certificates are picked by.
+    pub(crate) fn current(&self) -> (TlsAcceptor, Arc<SniResolver>) {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Loads the configured files again and swaps them in. On failure the
+    /// current certificates stay in use.
+    pub fn reload(&self) -> io::Result<()> {
+        let current = build(&self.config)?;
+        *self.current.write().unwrap() = current;
+        Ok(())
+    }
+
+    /// Reloads on SIGHUP, and whenever one of the configured files changes
+    /// if `reload_interval` is set. Runs until `shutdown` is triggered.
+    pub fn watch(self: Arc<Self>, shutdown: Shutdown) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut ticker = self.config.reload_interval.map(tokio::time::interval);
+
+        tokio::spawn(async move {
+            let mut snapshot = self.snapshot();
+            loop {
+                tokio::select! {
+                    Some(_) = hangup.recv() => {
+                        self.logger
+                            .log(LogLevel::Info, "SIGHUP received, reloading TLS certificates");
+                        snapshot = self.snapshot();
+                        self.try_reload();
+                    }
+                    _ = tick(&mut ticker) => {
+                        let current = self.snapshot();
+                        // Certificates and keys are often rewritten one after
+                        // the other. A reload caught in between fails and is
+                        // retried when the second file changes.
+                        if current != snapshot {
+                            snapshot = current;
+                            self.try_reload();
+                        }
+                    }
+                    _ = shutdown.triggered() => return,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn try_reload(&self) {
+        match self.reload() {
+            Ok(()) => self.logger.log(
+                LogLevel::Info,
+                &format!(
+                    "Reloaded TLS certificates from {}",
+                    self.config.cert_path.display()
+                ),
+            ),
+            Err(e) => self.logger.log(
+                LogLevel::Error,
+                &format!(
+                    "Failed to reload TLS certificates, keeping the old ones: {}",
+                    e
+                ),
+            ),
+        }
+    }
+
+    fn watched_files(&self) -> Vec<&PathBuf> {
+        let config = &self.config;
+        let mut files = vec![&config.cert_path, &config.key_path];
+        for cert in &config.certificates {
+            files.push(&cert.cert_path);
+            files.push(&cert.key_path);
+        }
+        if let Some(client_auth) = &config.client_auth {
+            files.push(&client_auth.ca_path);
+        }
+        files
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.watched_files()
+            .into_iter()
+            .map(|path| {
+                let metadata = std::fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+}
+
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
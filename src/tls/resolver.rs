@@ -0,0 +1,141 @@
+use std::{collections::HashMap, fmt, io, sync::Arc};
+
+use rustls::{
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use super::acceptor::{invalid, load_certs, load_key};
+use super::{SniCertificate, TlsConfig};
+
+/// Picks the certificate for a handshake from the SNI hostname. Exact names
+/// win over wildcards, and anything unmatched gets the default certificate.
+pub struct SniResolver {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// Keyed by the domain after `*.`, so `*.example.com` is `example.com`.
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    pub fn new(config: &TlsConfig, provider: &CryptoProvider) -> io::Result<Self> {
+        let default = certified_key(&config.default_certificate(), provider)?;
+        let mut resolver = Self {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            default,
+        };
+
+        for cert in &config.certificates {
+            let key = certified_key(cert, provider)?;
+            let hostname = cert.hostname.trim_end_matches('.').to_lowercase();
+            match hostname.strip_prefix("*.") {
+                Some(domain) => resolver.wildcard.insert(domain.to_string(), key),
+                None => resolver.exact.insert(hostname, key),
+            };
+        }
+        Ok(resolver)
+    }
+
+    /// The certificate served for `server_name`, along with the configured
+    /// hostname that picked it, such as `api.example.com` or
+    /// `*.example.com`. The hostname is `None` when the default certificate
+    /// answers.
+    pub fn lookup(&self, server_name: Option<&str>) -> (Option<String>, Arc<CertifiedKey>) {
+        let found = server_name.and_then(|name| {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if let Some(key) = self.exact.get(&name) {
+                return Some((name, key));
+            }
+            // A wildcard only covers a single label.
+            let (_, parent) = name.split_once('.')?;
+            let key = self.wildcard.get(parent)?;
+            Some((format!("*.{}", parent), key))
+        });
+        match found {
+            Some((hostname, key)) => (Some(hostname), Arc::clone(key)),
+            None => (None, Arc::clone(&self.default)),
+        }
+    }
+
+    pub fn hostnames(&self) -> impl Iterator<Item = String> + '_ {
+        self.exact
+            .keys()
+            .cloned()
+            .chain(self.wildcard.keys().map(|domain| format!("*.{}", domain)))
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let (_, key) = self.lookup(client_hello.server_name());
+        Some(key)
+    }
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniResolver")
+            .field("hostnames", &self.hostnames().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Loads a certificate chain and its key. The key type (RSA, ECDSA or
+/// Ed25519) is detected from the key itself.
+fn certified_key(
+    cert: &SniCertificate,
+    provider: &CryptoProvider,
+) -> io::Result<Arc<CertifiedKey>> {
+    let certs = load_certs(&cert.cert_path)?;
+    let key = load_key(&cert.key_path)?;
+    let key = CertifiedKey::from_der(certs, key, provider).map_err(|e| {
+        invalid(format!(
+            "{} and {}: {}",
+            cert.cert_path.display(),
+            cert.key_path.display(),
+            e
+        ))
+    })?;
+    Ok(Arc::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::dev_certificate;
+
+    #[test]
+    fn wildcards_cover_a_single_label() {
+        let dir = std::env::temp_dir().join(format!("sni-resolver-{}", std::process::id()));
+        let (cert, key) = dev_certificate(&dir).unwrap();
+        let config = TlsConfig::new(&cert, &key)
+            .certificate("api.example.com", &cert, &key)
+            .certificate("*.Example.com", &cert, &key)
+            .certificate("example.org.", &cert, &key);
+        let provider = rustls::crypto::ring::default_provider();
+        let resolver = SniResolver::new(&config, &provider).unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+
+        let (_, default) = resolver.lookup(None);
+        let matched = |name: &str| {
+            let (hostname, key) = resolver.lookup(Some(name));
+            assert_eq!(hostname.is_none(), Arc::ptr_eq(&key, &default));
+            hostname
+        };
+        assert_eq!(
+            matched("api.example.com").as_deref(),
+            Some("api.example.com")
+        );
+        assert_eq!(
+            matched("API.Example.COM.").as_deref(),
+            Some("api.example.com")
+        );
+        assert_eq!(matched("www.example.com").as_deref(), Some("*.example.com"));
+        assert_eq!(matched("example.org").as_deref(), Some("example.org"));
+        assert_eq!(matched("a.b.example.com"), None);
+        assert_eq!(matched("example.com"), None);
+        assert_eq!(matched("www.example.org"), None);
+    }
+}
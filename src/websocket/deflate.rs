@@ -0,0 +1,355 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::frame::close_code;
+
+/// Every message compressed with a sync flush ends in these bytes, which are
+/// left off on the wire (RFC 7692 section 7.2.1).
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Smallest window zlib can use for raw deflate streams.
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// Server side settings for the `permessage-deflate` extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateConfig {
+    /// Reset our compressor after every message, trading ratio for memory.
+    pub server_no_context_takeover: bool,
+    /// Ask clients to reset their compressor after every message.
+    pub client_no_context_takeover: bool,
+    /// LZ77 window we compress with, from 9 to 15.
+    pub server_max_window_bits: u8,
+    /// Window we ask clients to limit themselves to, from 9 to 15.
+    pub client_max_window_bits: u8,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        Self {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            server_max_window_bits: MAX_WINDOW_BITS,
+            client_max_window_bits: MAX_WINDOW_BITS,
+        }
+    }
+}
+
+/// Parameters agreed with the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: u8,
+    /// Only set when the client offered `client_max_window_bits`.
+    pub client_max_window_bits: Option<u8>,
+    /// Whether `server_max_window_bits` has to be echoed in the response.
+    server_window_offered: bool,
+}
+
+impl DeflateParams {
+    /// Picks the first offer in `Sec-WebSocket-Extensions` that we can
+    /// accept within `config`.
+    pub fn negotiate(offers: &str, config: &DeflateConfig) -> Option<Self> {
+        offers
+            .split(',')
+            .find_map(|offer| Self::accept_offer(offer, config))
+    }
+
+    fn accept_offer(offer: &str, config: &DeflateConfig) -> Option<Self> {
+        let mut parts = offer.split(';').map(str::trim);
+        if parts.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let server_bits = config
+            .server_max_window_bits
+            .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS);
+        let client_bits = config
+            .client_max_window_bits
+            .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS);
+
+        let mut params = DeflateParams {
+            server_no_context_takeover: config.server_no_context_takeover,
+            client_no_context_takeover: config.client_no_context_takeover,
+            server_max_window_bits: server_bits,
+            client_max_window_bits: None,
+            server_window_offered: false,
+        };
+        let mut seen = Vec::new();
+
+        for param in parts {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+            // Offers repeating a parameter have to be declined.
+            if seen.contains(&name) {
+                return None;
+            }
+            seen.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => params.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => params.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(value)) => {
+                    let bits = parse_window_bits(value)?;
+                    // zlib can't produce raw deflate with an 8 bit window.
+                    if bits < MIN_WINDOW_BITS {
+                        return None;
+                    }
+                    params.server_max_window_bits = server_bits.min(bits);
+                    params.server_window_offered = true;
+                }
+                ("client_max_window_bits", None) => {
+                    params.client_max_window_bits = Some(client_bits);
+                }
+                ("client_max_window_bits", Some(value)) => {
+                    let bits = parse_window_bits(value)?;
+                    params.client_max_window_bits = Some(client_bits.min(bits));
+                }
+                _ => return None,
+            }
+        }
+
+        Some(params)
+    }
+
+    /// The `Sec-WebSocket-Extensions` value accepting these parameters.
+    pub fn response_header(&self) -> String {
+        let mut header = "permessage-deflate".to_string();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_window_offered {
+            header.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+        if let Some(bits) = self.client_max_window_bits {
+            header.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+fn parse_window_bits(value: &str) -> Option<u8> {
+    // Leading zeros aren't allowed by the grammar.
+    if value.starts_with('0') {
+        return None;
+    }
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+/// Compresses outgoing and decompresses incoming messages for one
+/// connection.
+pub struct Deflater {
+    compress: Compress,
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(params: &DeflateParams) -> Self {
+        Self {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.server_max_window_bits,
+            ),
+            // Messages compressed with a smaller window decode fine with the
+            // largest one, so the client's setting doesn't matter here.
+            decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            no_context_takeover: params.server_no_context_takeover,
+        }
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            if out.capacity() - out.len() < 64 {
+                out.reserve(out.capacity().max(1024));
+            }
+            let consumed = (self.compress.total_in() - start) as usize;
+            // Compressing into a Vec only fails if the stream state is
+            // corrupt, which can't happen with our own input.
+            let _ = self
+                .compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync);
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        } else if out.is_empty() {
+            // With nothing new to flush zlib writes no empty block at all,
+            // but the client appends the tail of one either way. What's left
+            // of that block once its tail is cut is a single zero byte.
+            out.push(0x00);
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        out
+    }
+
+    /// Inflates a compressed message. Errors carry the close code to fail
+    /// the connection with.
+    pub fn decompress(&mut self, mut data: Vec<u8>, max_size: usize) -> Result<Vec<u8>, u16> {
+        data.extend_from_slice(&TAIL);
+        let mut out = Vec::with_capacity(data.len() * 2);
+        let start = self.decompress.total_in();
+
+        loop {
+            if out.capacity() - out.len() < 1024 {
+                out.reserve(out.capacity().max(4096));
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|_| close_code::INVALID_PAYLOAD)?;
+
+            if out.len() > max_size {
+                return Err(close_code::MESSAGE_TOO_BIG);
+            }
+            // A client may end its deflate stream with a final block, after
+            // which the next message starts a fresh one.
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(out);
+            }
+            let now_consumed = (self.decompress.total_in() - start) as usize;
+            if now_consumed == data.len() && out.len() < out.capacity() {
+                return Ok(out);
+            }
+            if now_consumed == consumed && out.len() == produced {
+                return Err(close_code::INVALID_PAYLOAD);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(offer: &str) -> DeflateParams {
+        DeflateParams::negotiate(offer, &DeflateConfig::default()).unwrap()
+    }
+
+    fn deflater() -> Deflater {
+        Deflater::new(&params("permessage-deflate"))
+    }
+
+    // The examples of RFC 7692 section 7.2.3, all of them "Hello".
+    #[test]
+    fn decompresses_rfc_7692_examples() {
+        let messages: [&[u8]; 4] = [
+            &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+            // Stored without compression.
+            &[
+                0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00,
+            ],
+            // Split over two deflate blocks.
+            &[
+                0xf2, 0x48, 0x05, 0x00, 0x00, 0x00, 0xff, 0xff, 0xca, 0xc9, 0xc9, 0x07, 0x00,
+            ],
+            // Ending in a final block.
+            &[0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00],
+        ];
+        for message in messages {
+            assert_eq!(
+                deflater().decompress(message.to_vec(), 1024),
+                Ok(b"Hello".to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn later_messages_refer_back_to_earlier_ones() {
+        let mut deflater = deflater();
+        let first = vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        let second = vec![0xf2, 0x00, 0x11, 0x00, 0x00];
+        assert_eq!(deflater.decompress(first, 1024), Ok(b"Hello".to_vec()));
+        assert_eq!(deflater.decompress(second, 1024), Ok(b"Hello".to_vec()));
+    }
+
+    #[test]
+    fn compressed_messages_round_trip() {
+        let (mut server, mut client) = (deflater(), deflater());
+        let messages = [
+            b"Hello".to_vec(),
+            b"Hello".to_vec(),
+            Vec::new(),
+            (0..100_000u32).map(|i| (i % 251) as u8).collect(),
+        ];
+        for message in messages {
+            let compressed = server.compress(&message);
+            assert!(!compressed.is_empty() && !compressed.ends_with(&TAIL));
+            assert_eq!(client.decompress(compressed, 1 << 20), Ok(message));
+        }
+    }
+
+    #[test]
+    fn no_context_takeover_compresses_each_message_alone() {
+        let mut deflater = Deflater::new(&params("permessage-deflate; server_no_context_takeover"));
+        let first = deflater.compress(b"Hello");
+        assert_eq!(deflater.compress(b"Hello"), first);
+        assert_eq!(first, [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn rejects_oversized_and_invalid_messages() {
+        let bomb = deflater().compress(&vec![0; 1 << 20]);
+        assert_eq!(
+            deflater().decompress(bomb, 1000),
+            Err(close_code::MESSAGE_TOO_BIG)
+        );
+        assert_eq!(
+            deflater().decompress(vec![0xff, 0xff, 0xff], 1024),
+            Err(close_code::INVALID_PAYLOAD)
+        );
+    }
+
+    #[test]
+    fn negotiates_the_first_acceptable_offer() {
+        let config = DeflateConfig::default();
+        let negotiate = |offers: &str| {
+            DeflateParams::negotiate(offers, &config).map(|params| params.response_header())
+        };
+
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits").as_deref(),
+            Some("permessage-deflate; client_max_window_bits=15")
+        );
+        assert_eq!(
+            negotiate("x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=10")
+                .as_deref(),
+            Some("permessage-deflate; server_max_window_bits=10")
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=8, permessage-deflate")
+                .as_deref(),
+            Some("permessage-deflate")
+        );
+        for declined in [
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; server_max_window_bits=09",
+            "permessage-deflate; client_max_window_bits=16",
+            "permessage-deflate; unknown",
+        ] {
+            assert_eq!(negotiate(declined), None, "{}", declined);
+        }
+    }
+}
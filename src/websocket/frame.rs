@@ -0,0 +1,288 @@
+/// Largest payload a control frame may carry.
+pub const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xa => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xa,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+/// RFC 6455 section 7.4.1 status codes sent in close frames.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const NO_STATUS: u16 = 1005;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+
+    /// Whether `code` may appear in a close frame on the wire.
+    pub fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub rsv1: bool,
+    pub opcode: OpCode,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: OpCode, payload: Vec<u8>) -> Self {
+        Self {
+            fin: true,
+            rsv1: false,
+            opcode,
+            payload,
+        }
+    }
+
+    pub fn close(code: u16, reason: &str) -> Self {
+        // The reason has to stay valid UTF-8 if it's cut short.
+        let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        Frame::new(OpCode::Close, payload)
+    }
+
+    /// Reads one client frame from the start of `buffer` and unmasks it.
+    /// Returns `Ok(None)` until the whole frame has arrived, and the number of
+    /// bytes it used. Errors carry the close code to fail the connection with.
+    pub fn parse(buffer: &[u8], max_payload: usize) -> Result<Option<(Frame, usize)>, u16> {
+        if buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buffer[0] & 0x80 != 0;
+        let rsv1 = buffer[0] & 0x40 != 0;
+        if buffer[0] & 0x30 != 0 {
+            return Err(close_code::PROTOCOL_ERROR);
+        }
+        let opcode = OpCode::from_u8(buffer[0] & 0x0f).ok_or(close_code::PROTOCOL_ERROR)?;
+
+        // Clients must mask every frame they send.
+        if buffer[1] & 0x80 == 0 {
+            return Err(close_code::PROTOCOL_ERROR);
+        }
+
+        let (len, mut pos) = match buffer[1] & 0x7f {
+            126 => match buffer.get(2..4) {
+                Some(b) => (u16::from_be_bytes([b[0], b[1]]) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match buffer.get(2..10) {
+                Some(b) => (u64::from_be_bytes(b.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            len => (len as u64, 2),
+        };
+
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(close_code::PROTOCOL_ERROR);
+        }
+        if len > max_payload as u64 {
+            return Err(close_code::MESSAGE_TOO_BIG);
+        }
+        let len = len as usize;
+
+        let mask = match buffer.get(pos..pos + 4) {
+            Some(mask) => [mask[0], mask[1], mask[2], mask[3]],
+            None => return Ok(None),
+        };
+        pos += 4;
+
+        let payload = match buffer.get(pos..pos + len) {
+            Some(payload) => payload
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ mask[i % 4])
+                .collect(),
+            None => return Ok(None),
+        };
+
+        let frame = Frame {
+            fin,
+            rsv1,
+            opcode,
+            payload,
+        };
+        Ok(Some((frame, pos + len)))
+    }
+
+    /// Writes the frame unmasked, as servers send them.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let mut first = self.opcode.as_u8();
+        if self.fin {
+            first |= 0x80;
+        }
+        if self.rsv1 {
+            first |= 0x40;
+        }
+        out.push(first);
+
+        let len = self.payload.len();
+        if len < 126 {
+            out.push(len as u8);
+        } else if len <= u16::MAX as usize {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        out.extend_from_slice(&self.payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples of RFC 6455 section 5.7.
+    const MASKED_HELLO: &[u8] = &[
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+
+    #[test]
+    fn unmasks_client_frames() {
+        let (frame, used) = Frame::parse(MASKED_HELLO, 1024).unwrap().unwrap();
+        assert_eq!(used, MASKED_HELLO.len());
+        assert_eq!(frame, Frame::new(OpCode::Text, b"Hello".to_vec()));
+
+        let mut pong = MASKED_HELLO.to_vec();
+        pong[0] = 0x8a;
+        let (frame, _) = Frame::parse(&pong, 1024).unwrap().unwrap();
+        assert_eq!(frame, Frame::new(OpCode::Pong, b"Hello".to_vec()));
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        for end in 0..MASKED_HELLO.len() {
+            assert_eq!(Frame::parse(&MASKED_HELLO[..end], 1024), Ok(None));
+        }
+    }
+
+    #[test]
+    fn rejects_unmasked_client_frames() {
+        let unmasked = [0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert_eq!(
+            Frame::parse(&unmasked, 1024),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+    }
+
+    #[test]
+    fn keeps_fragments_apart() {
+        let mut buffer = vec![0x01, 0x83, 0, 0, 0, 0];
+        buffer.extend_from_slice(b"Hel");
+        buffer.extend_from_slice(&[0x80, 0x82, 0, 0, 0, 0]);
+        buffer.extend_from_slice(b"lo");
+
+        let (first, used) = Frame::parse(&buffer, 1024).unwrap().unwrap();
+        assert!(!first.fin);
+        assert_eq!(first.opcode, OpCode::Text);
+        assert_eq!(first.payload, b"Hel");
+
+        let (last, _) = Frame::parse(&buffer[used..], 1024).unwrap().unwrap();
+        assert!(last.fin);
+        assert_eq!(last.opcode, OpCode::Continuation);
+        assert_eq!(last.payload, b"lo");
+    }
+
+    #[test]
+    fn rejects_invalid_control_frames() {
+        // Fragmented ping.
+        assert_eq!(
+            Frame::parse(&[0x09, 0x80, 0, 0, 0, 0], 1024),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+        // Ping over 125 bytes.
+        assert_eq!(
+            Frame::parse(&[0x89, 0xfe, 0x00, 0x7e], 1024),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+        // Reserved bits without an extension, and a reserved opcode.
+        assert_eq!(
+            Frame::parse(&[0xa1, 0x80, 0, 0, 0, 0], 1024),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+        assert_eq!(
+            Frame::parse(&[0x83, 0x80, 0, 0, 0, 0], 1024),
+            Err(close_code::PROTOCOL_ERROR)
+        );
+    }
+
+    #[test]
+    fn rejects_payloads_over_the_limit() {
+        let mut header = vec![0x82, 0xff];
+        header.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            Frame::parse(&header, 1024),
+            Err(close_code::MESSAGE_TOO_BIG)
+        );
+    }
+
+    #[test]
+    fn encodes_unmasked_with_the_shortest_length() {
+        let mut out = Vec::new();
+        Frame::new(OpCode::Text, b"Hello".to_vec()).encode(&mut out);
+        assert_eq!(out, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        let mut out = Vec::new();
+        Frame::new(OpCode::Binary, vec![0; 256]).encode(&mut out);
+        assert_eq!(out[..4], [0x82, 0x7e, 0x01, 0x00]);
+        assert_eq!(out.len(), 4 + 256);
+
+        let mut out = Vec::new();
+        Frame::new(OpCode::Binary, vec![0; 65536]).encode(&mut out);
+        assert_eq!(out[..10], [0x82, 0x7f, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn close_reason_is_cut_on_a_char_boundary() {
+        let frame = Frame::close(close_code::NORMAL, &"é".repeat(100));
+        assert!(frame.payload.len() <= MAX_CONTROL_PAYLOAD);
+        assert!(std::str::from_utf8(&frame.payload[2..]).is_ok());
+    }
+}
@@ -0,0 +1,81 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+
+use super::{WebSocketHandler, WebSocketUpgrade};
+use crate::http::{Context, HttpVersion, Res, ResponseBuilder};
+
+/// Appended to the client's key before hashing, per RFC 6455 section 1.3.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const VERSION: &str = "13";
+
+/// Computes `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Validates the opening handshake in `context.request`. Returns the 101
+/// response carrying the pending upgrade, or the error response to send
+/// instead.
+pub fn accept(context: Context, handler: WebSocketHandler) -> Res {
+    let request = &context.request;
+
+    if request.version != HttpVersion::Http11
+        || !request.has_token("upgrade", "websocket")
+        || !request.has_token("connection", "upgrade")
+    {
+        return Res::new(
+            ResponseBuilder::bad_request()
+                .text("Expected a WebSocket upgrade")
+                .build(),
+            400,
+        );
+    }
+
+    if request
+        .headers
+        .get("sec-websocket-version")
+        .map(|v| v.trim())
+        != Some(VERSION)
+    {
+        return Res::new(
+            ResponseBuilder::new()
+                .status(ResponseBuilder::UPGRADE_REQUIRED)
+                .header("Sec-WebSocket-Version", VERSION)
+                .text("Unsupported WebSocket version")
+                .build(),
+            426,
+        );
+    }
+
+    // The key has to be 16 random bytes, base64 encoded.
+    let key = match request.headers.get("sec-websocket-key") {
+        Some(key) if STANDARD.decode(key.trim()).is_ok_and(|k| k.len() == 16) => key.trim(),
+        _ => {
+            return Res::new(
+                ResponseBuilder::bad_request()
+                    .text("Invalid Sec-WebSocket-Key")
+                    .build(),
+                400,
+            )
+        }
+    };
+
+    let response = ResponseBuilder::new()
+        .status(ResponseBuilder::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", &accept_key(key))
+        .build();
+
+    let mut res = Res::new(response, 101);
+    res.websocket = Some(Box::new(WebSocketUpgrade {
+        handler,
+        context,
+        deflate: None,
+    }));
+    res
+}
@@ -0,0 +1,12 @@
+mod deflate;
+pub mod frame;
+mod handshake;
+mod socket;
+
+pub use deflate::{DeflateConfig, DeflateParams};
+pub use frame::close_code;
+pub use handshake::accept_key;
+pub(crate) use handshake::accept;
+pub use socket::{
+    Message, WebSocket, WebSocketFuture, WebSocketHandler, WebSocketSender, WebSocketUpgrade,
+};
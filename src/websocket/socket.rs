@@ -0,0 +1,576 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep_until, Instant},
+};
+
+use super::{
+    deflate::{DeflateConfig, DeflateParams, Deflater},
+    frame::{close_code, Frame, OpCode},
+};
+use crate::{
+    connection::within,
+    http::Context,
+    logger::{LogLevel, Logger},
+    shutdown::Shutdown,
+};
+
+/// How long to wait for the client to answer our close frame before
+/// dropping the connection anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages the handler hasn't sent yet. Sending waits once this many are
+/// queued.
+const OUTGOING_CAPACITY: usize = 32;
+
+pub type WebSocketFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs for the lifetime of a WebSocket connection. The connection is closed
+/// once the returned future completes.
+pub type WebSocketHandler = fn(WebSocket) -> WebSocketFuture;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+enum Outgoing {
+    Message(Message),
+    Close(u16, String),
+}
+
+/// Sends messages on a WebSocket. It can be cloned and moved to other tasks
+/// while the `WebSocket` keeps receiving.
+#[derive(Debug, Clone)]
+pub struct WebSocketSender {
+    tx: mpsc::Sender<Outgoing>,
+}
+
+impl WebSocketSender {
+    /// Queues `message` to be sent. Fails once the connection has closed.
+    pub async fn send(&self, message: impl Into<Message>) -> io::Result<()> {
+        self.tx
+            .send(Outgoing::Message(message.into()))
+            .await
+            .map_err(|_| closed())
+    }
+
+    /// Starts the close handshake with the given status code and reason.
+    pub async fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        self.tx
+            .send(Outgoing::Close(code, reason.to_string()))
+            .await
+            .map_err(|_| closed())
+    }
+}
+
+/// An accepted WebSocket connection, as handed to a `WebSocketHandler`.
+/// Pings and the close handshake are answered automatically.
+pub struct WebSocket {
+    context: Context,
+    incoming: mpsc::Receiver<Message>,
+    sender: WebSocketSender,
+}
+
+impl WebSocket {
+    /// The request that opened the connection, with its route parameters.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Waits for the next complete message. Returns `None` once the client
+    /// has closed the connection.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.incoming.recv().await
+    }
+
+    pub async fn send(&self, message: impl Into<Message>) -> io::Result<()> {
+        self.sender.send(message).await
+    }
+
+    pub fn sender(&self) -> WebSocketSender {
+        self.sender.clone()
+    }
+
+    pub async fn close(self, code: u16, reason: &str) -> io::Result<()> {
+        self.sender.close(code, reason).await
+    }
+}
+
+/// A WebSocket handshake that has been accepted but not yet switched to.
+pub struct WebSocketUpgrade {
+    pub(crate) handler: WebSocketHandler,
+    pub(crate) context: Context,
+    pub(crate) deflate: Option<DeflateParams>,
+}
+
+impl WebSocketUpgrade {
+    /// Accepts `permessage-deflate` if the client offered it, returning the
+    /// `Sec-WebSocket-Extensions` value for the 101 response.
+    pub(crate) fn negotiate_deflate(&mut self, config: &DeflateConfig) -> Option<String> {
+        let offers = self
+            .context
+            .request
+            .headers
+            .get("sec-websocket-extensions")?;
+        let params = DeflateParams::negotiate(offers, config)?;
+        self.deflate = Some(params);
+        Some(params.response_header())
+    }
+
+    /// Runs the connection over `stream` once the 101 response has been
+    /// sent. `buffer` holds anything the client sent after the handshake.
+    /// Once `shutdown` is triggered the client is sent a going-away close.
+    pub(crate) async fn serve<S>(
+        self,
+        stream: S,
+        buffer: BytesMut,
+        max_message_size: usize,
+        write_timeout: Duration,
+        shutdown: Shutdown,
+        ip: String,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (incoming_tx, incoming) = mpsc::channel(1);
+        let (outgoing_tx, outgoing) = mpsc::channel(OUTGOING_CAPACITY);
+
+        let socket = WebSocket {
+            context: self.context,
+            incoming,
+            sender: WebSocketSender { tx: outgoing_tx },
+        };
+        tokio::spawn((self.handler)(socket));
+
+        let mut driver = Driver {
+            stream,
+            buffer,
+            max_message_size,
+            write_timeout,
+            shutdown,
+            ip,
+            logger: Logger::new(),
+            deflate: self.deflate.as_ref().map(Deflater::new),
+            fragments: None,
+            pending: None,
+            held: VecDeque::new(),
+            held_size: 0,
+            incoming: incoming_tx,
+            outgoing,
+            out: Vec::new(),
+            close_deadline: None,
+        };
+        driver.run().await
+    }
+}
+
+/// What to do after handling a frame.
+enum Flow {
+    Continue,
+    Done,
+}
+
+struct Driver<S> {
+    stream: S,
+    buffer: BytesMut,
+    max_message_size: usize,
+    /// How long a write may wait on the client before the connection is
+    /// dropped.
+    write_timeout: Duration,
+    shutdown: Shutdown,
+    ip: String,
+    logger: Logger,
+
+    deflate: Option<Deflater>,
+    /// Opcode, compression flag and data of a fragmented message still
+    /// being received.
+    fragments: Option<(OpCode, bool, Vec<u8>)>,
+    /// A complete message the handler hasn't taken yet.
+    pending: Option<Message>,
+    /// Data frames that arrived while a message was pending, handled once
+    /// the handler takes it. Control frames behind them are still answered
+    /// meanwhile. Reading stops when another frame wouldn't fit under
+    /// `max_message_size`, so a slow handler pushes back on the client.
+    held: VecDeque<Frame>,
+    held_size: usize,
+    incoming: mpsc::Sender<Message>,
+    outgoing: mpsc::Receiver<Outgoing>,
+
+    out: Vec<u8>,
+    /// Set once we've sent a close frame and are waiting for the reply.
+    close_deadline: Option<Instant>,
+}
+
+impl<S> Driver<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn run(&mut self) -> io::Result<()> {
+        loop {
+            while self.pending.is_none() {
+                let Some(frame) = self.held.pop_front() else {
+                    break;
+                };
+                self.held_size -= frame.payload.len();
+                if let Flow::Done = self.on_frame(frame) {
+                    return self.flush().await;
+                }
+            }
+
+            let mut stalled = false;
+            loop {
+                match Frame::parse(&self.buffer, self.max_message_size) {
+                    Ok(Some((frame, used))) => {
+                        if self.pending.is_some() && !frame.opcode.is_control() {
+                            if self.held_size + frame.payload.len() > self.max_message_size {
+                                stalled = true;
+                                break;
+                            }
+                            self.buffer.advance(used);
+                            self.held_size += frame.payload.len();
+                            self.held.push_back(frame);
+                            continue;
+                        }
+                        self.buffer.advance(used);
+                        if let Flow::Done = self.on_frame(frame) {
+                            return self.flush().await;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(code) => return self.fail(code).await,
+                }
+            }
+
+            self.flush().await?;
+
+            let closing = self.close_deadline.is_some();
+            let deadline = self.close_deadline.unwrap_or_else(Instant::now);
+
+            let outgoing = tokio::select! {
+                permit = self.incoming.reserve(), if self.pending.is_some() => {
+                    let message = self.pending.take();
+                    // If the handler stopped listening the message is dropped.
+                    if let (Ok(permit), Some(message)) = (permit, message) {
+                        permit.send(message);
+                    }
+                    continue;
+                }
+                read = self.stream.read_buf(&mut self.buffer), if !stalled => {
+                    if 0 == read? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                outgoing = self.outgoing.recv(), if !closing => outgoing,
+                _ = self.shutdown.triggered(), if !closing => {
+                    Some(Outgoing::Close(close_code::GOING_AWAY, String::new()))
+                }
+                _ = sleep_until(deadline), if closing => return Ok(()),
+            };
+
+            match outgoing {
+                Some(Outgoing::Message(Message::Text(text))) => {
+                    self.send_message(OpCode::Text, text.into_bytes());
+                }
+                Some(Outgoing::Message(Message::Binary(data))) => {
+                    self.send_message(OpCode::Binary, data);
+                }
+                Some(Outgoing::Close(code, reason)) => self.start_close(code, &reason),
+                // The handler has finished, so the connection is done too.
+                None => self.start_close(close_code::NORMAL, ""),
+            }
+        }
+    }
+
+    fn send_message(&mut self, opcode: OpCode, data: Vec<u8>) {
+        match self.deflate.as_mut() {
+            Some(deflater) => {
+                let mut frame = Frame::new(opcode, deflater.compress(&data));
+                frame.rsv1 = true;
+                self.queue(frame);
+            }
+            None => self.queue(Frame::new(opcode, data)),
+        }
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Flow {
+        // RSV1 marks a compressed message, so it's only valid on the first
+        // frame of a data message once permessage-deflate is agreed.
+        let first_data_frame = matches!(frame.opcode, OpCode::Text | OpCode::Binary);
+        if frame.rsv1 && (self.deflate.is_none() || !first_data_frame) {
+            return self.protocol_error("unexpected RSV1 bit");
+        }
+
+        match frame.opcode {
+            OpCode::Ping => {
+                if self.close_deadline.is_none() {
+                    self.queue(Frame::new(OpCode::Pong, frame.payload));
+                }
+                Flow::Continue
+            }
+            OpCode::Pong => Flow::Continue,
+            OpCode::Close => self.on_close(frame.payload),
+            OpCode::Text | OpCode::Binary => {
+                if self.fragments.is_some() {
+                    return self.protocol_error("new message inside a fragmented one");
+                }
+                if frame.fin {
+                    self.deliver(frame.opcode, frame.rsv1, frame.payload)
+                } else {
+                    self.fragments = Some((frame.opcode, frame.rsv1, frame.payload));
+                    Flow::Continue
+                }
+            }
+            OpCode::Continuation => {
+                let Some((opcode, compressed, mut data)) = self.fragments.take() else {
+                    return self.protocol_error("continuation without a message");
+                };
+                if data.len() + frame.payload.len() > self.max_message_size {
+                    self.start_close(close_code::MESSAGE_TOO_BIG, "");
+                    return Flow::Done;
+                }
+                data.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.deliver(opcode, compressed, data)
+                } else {
+                    self.fragments = Some((opcode, compressed, data));
+                    Flow::Continue
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, opcode: OpCode, compressed: bool, data: Vec<u8>) -> Flow {
+        // Messages that arrive after we started closing are discarded.
+        if self.close_deadline.is_some() {
+            return Flow::Continue;
+        }
+
+        let data = match (compressed, self.deflate.as_mut()) {
+            (true, Some(deflater)) => match deflater.decompress(data, self.max_message_size) {
+                Ok(data) => data,
+                Err(code) => {
+                    self.start_close(code, "");
+                    return Flow::Done;
+                }
+            },
+            _ => data,
+        };
+
+        let message = if opcode == OpCode::Text {
+            match String::from_utf8(data) {
+                Ok(text) => Message::Text(text),
+                Err(_) => {
+                    self.start_close(close_code::INVALID_PAYLOAD, "invalid UTF-8");
+                    return Flow::Done;
+                }
+            }
+        } else {
+            Message::Binary(data)
+        };
+        self.pending = Some(message);
+        Flow::Continue
+    }
+
+    fn on_close(&mut self, payload: Vec<u8>) -> Flow {
+        if self.close_deadline.is_some() {
+            // This answers the close frame we sent.
+            return Flow::Done;
+        }
+
+        let code = match payload.len() {
+            0 => close_code::NORMAL,
+            1 => return self.protocol_error("truncated close frame"),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !close_code::is_valid(code) {
+                    return self.protocol_error("invalid close code");
+                }
+                if std::str::from_utf8(&payload[2..]).is_err() {
+                    self.start_close(close_code::INVALID_PAYLOAD, "");
+                    return Flow::Done;
+                }
+                code
+            }
+        };
+
+        self.queue(Frame::close(code, ""));
+        Flow::Done
+    }
+
+    fn protocol_error(&mut self, reason: &str) -> Flow {
+        self.logger.log(
+            LogLevel::Warning,
+            &format!("WebSocket protocol error from {}: {}", self.ip, reason),
+        );
+        self.start_close(close_code::PROTOCOL_ERROR, "");
+        Flow::Done
+    }
+
+    fn start_close(&mut self, code: u16, reason: &str) {
+        self.queue(Frame::close(code, reason));
+        self.close_deadline = Some(Instant::now() + CLOSE_TIMEOUT);
+    }
+
+    async fn fail(&mut self, code: u16) -> io::Result<()> {
+        self.logger.log(
+            LogLevel::Warning,
+            &format!("Closing WebSocket from {}: code {}", self.ip, code),
+        );
+        self.queue(Frame::close(code, ""));
+        self.flush().await
+    }
+
+    fn queue(&mut self, frame: Frame) {
+        frame.encode(&mut self.out);
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if !self.out.is_empty() {
+            within(self.write_timeout, self.stream.write_all(&self.out)).await?;
+            self.out.clear();
+        }
+        within(self.write_timeout, self.stream.flush()).await
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket connection closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    type Channels = (mpsc::Sender<Outgoing>, mpsc::Receiver<Message>);
+
+    fn driver(
+        stream: DuplexStream,
+        write_timeout: Duration,
+        shutdown: Shutdown,
+    ) -> (Driver<DuplexStream>, Channels) {
+        let (incoming_tx, incoming) = mpsc::channel(1);
+        let (outgoing_tx, outgoing) = mpsc::channel(OUTGOING_CAPACITY);
+        let driver = Driver {
+            stream,
+            buffer: BytesMut::new(),
+            max_message_size: 1 << 20,
+            write_timeout,
+            shutdown,
+            ip: "test".to_string(),
+            logger: Logger::new(),
+            deflate: None,
+            fragments: None,
+            pending: None,
+            held: VecDeque::new(),
+            held_size: 0,
+            incoming: incoming_tx,
+            outgoing,
+            out: Vec::new(),
+            close_deadline: None,
+        };
+        (driver, (outgoing_tx, incoming))
+    }
+
+    /// A single masked client frame.
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut out = vec![first, 0x80 | payload.len() as u8];
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    #[tokio::test]
+    async fn fragments_are_joined_around_control_frames() {
+        let (mut client, server) = duplex(1024);
+        let (mut driver, (_outgoing, mut incoming)) =
+            driver(server, Duration::from_secs(1), Shutdown::new());
+        tokio::spawn(async move { driver.run().await });
+
+        let mut frames = masked(0x01, b"Hel");
+        frames.extend(masked(0x89, b"ping"));
+        frames.extend(masked(0x80, b"lo"));
+        client.write_all(&frames).await.unwrap();
+
+        assert_eq!(incoming.recv().await, Some(Message::Text("Hello".into())));
+        let mut pong = [0; 6];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x8a, 4, b'p', b'i', b'n', b'g']);
+    }
+
+    #[tokio::test]
+    async fn continuation_without_a_message_is_a_protocol_error() {
+        let (mut client, server) = duplex(1024);
+        let (mut driver, _channels) = driver(server, Duration::from_secs(1), Shutdown::new());
+        tokio::spawn(async move { driver.run().await });
+
+        client.write_all(&masked(0x80, b"lo")).await.unwrap();
+        let mut close = [0; 4];
+        client.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xea]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_with_going_away() {
+        let (mut client, server) = duplex(1024);
+        let shutdown = Shutdown::new();
+        let (mut driver, _channels) = driver(server, Duration::from_secs(1), shutdown.clone());
+        let running = tokio::spawn(async move { driver.run().await });
+
+        shutdown.trigger();
+        let mut close = [0; 4];
+        client.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xe9]);
+
+        client
+            .write_all(&masked(0x88, &close_code::GOING_AWAY.to_be_bytes()))
+            .await
+            .unwrap();
+        let stopped = tokio::time::timeout(Duration::from_secs(1), running).await;
+        assert!(stopped.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_that_stops_reading_times_out() {
+        let (_client, server) = duplex(64);
+        let write_timeout = Duration::from_millis(100);
+        let (mut driver, (outgoing, _incoming)) = driver(server, write_timeout, Shutdown::new());
+        outgoing
+            .send(Outgoing::Message(Message::Binary(vec![0; 4096])))
+            .await
+            .unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(1), driver.run()).await;
+        let error = result.expect("write timed out").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}